@@ -0,0 +1,5 @@
+-- Add migration script here
+ALTER TABLE transport_data
+    ADD COLUMN is_out BOOLEAN,
+    ADD COLUMN out_since TIMESTAMP,
+    ADD COLUMN rejoined_at TIMESTAMP;
@@ -0,0 +1,6 @@
+-- Rows are keyed by feed so ids from different operators don't collide
+ALTER TABLE transport_data ADD COLUMN feed TEXT NOT NULL DEFAULT '';
+
+DROP INDEX time_id;
+
+CREATE UNIQUE INDEX time_feed_id ON transport_data (feed, id, timestamp);
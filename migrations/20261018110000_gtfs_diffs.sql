@@ -0,0 +1,10 @@
+-- What changed on each static GTFS reload
+CREATE TABLE gtfs_diffs (
+    generated_at TIMESTAMP NOT NULL,
+    feed TEXT NOT NULL,
+    from_version TEXT,
+    to_version TEXT,
+    diff TEXT NOT NULL
+);
+
+CREATE INDEX gtfs_diffs_feed ON gtfs_diffs (feed, generated_at);
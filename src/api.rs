@@ -1,28 +1,44 @@
 use std::{net::SocketAddr, sync::Arc};
 
 use crate::{logger, store::Store};
-use axum::{http::Method, routing::get, Router};
+use axum::{http::Method, routing::get, Json, Router};
 use tower_http::cors::{Any, CorsLayer};
 
 mod gtfs;
 mod rt;
+mod schedule;
 mod static_serve;
+mod status;
+mod stops;
+mod vehicles;
 mod ws;
 
-pub async fn init(ip: String, port: String, store: Arc<Store>) {
+pub async fn init(ip: String, port: String, stores: Vec<Arc<Store>>) {
     let cors = CorsLayer::new()
         .allow_methods([Method::GET, Method::POST, Method::OPTIONS, Method::PUT])
         .allow_origin(Any)
         .allow_headers(Any);
 
-    let app = Router::new()
+    let namespaces = stores
+        .iter()
+        .map(|store| store.namespace().to_string())
+        .collect::<Vec<String>>();
+
+    let mut app = Router::new()
         .route("/", get(|| async { "Hello, World!" }))
-        .route("/raw", get(static_serve::serve))
-        .route("/ws", get(ws::websocket))
-        .route("/refresh_gtfs", get(gtfs::refresh))
-        .route("/avg_speed", get(rt::avg_speed))
-        .layer(cors)
-        .with_state(store);
+        .route("/feeds", get(|| async move { Json(namespaces) }));
+
+    //The feed without namespace (API_URL) is served at the root, the others
+    //under their namespace
+    for store in stores {
+        let routes = feed_routes(store.clone());
+        app = match store.namespace() {
+            "" => app.merge(routes),
+            namespace => app.nest(&format!("/feeds/{}", namespace), routes),
+        };
+    }
+
+    let app = app.layer(cors);
 
     let listener = match tokio::net::TcpListener::bind(format!("{}:{}", ip, port)).await {
         Ok(listener) => listener,
@@ -43,3 +59,28 @@ pub async fn init(ip: String, port: String, store: Arc<Store>) {
     .await
     .unwrap();
 }
+
+fn feed_routes(store: Arc<Store>) -> Router {
+    Router::new()
+        .route("/raw", get(static_serve::serve))
+        .route("/gtfs_rt", get(static_serve::serve_enriched))
+        .route("/ws", get(ws::websocket))
+        .route("/refresh_gtfs", get(gtfs::refresh))
+        .route("/status", get(status::status))
+        .route("/admin/validation", get(gtfs::validation))
+        .route("/gtfs/diffs", get(gtfs::diffs))
+        .route("/avg_speed", get(rt::avg_speed))
+        .route("/trip_updates", get(rt::trip_updates))
+        .route("/alerts", get(rt::alerts))
+        .route("/detours", get(rt::detours))
+        .route("/predictions/vehicle/:id", get(rt::vehicle_predictions))
+        .route("/predictions/stop/:stop_id", get(rt::stop_predictions))
+        .route("/schedule", get(schedule::summary))
+        .route("/schedule/trips", get(schedule::trips))
+        .route("/stops", get(stops::stops))
+        .route("/stops/nearest", get(stops::nearest))
+        .route("/stops/:stop_id/departures", get(stops::departures))
+        .route("/vehicles", get(vehicles::vehicles))
+        .route("/vehicles/:id", get(vehicles::vehicle))
+        .with_state(store)
+}
@@ -8,7 +8,7 @@ use axum::{
     Json,
 };
 use serde::Deserialize;
-use serde_json::json;
+use serde_json::{json, Value};
 
 #[derive(Deserialize)]
 pub struct Key {
@@ -39,13 +39,53 @@ pub async fn refresh(
     };
 
     match app.refresh_gtfs(key).await {
-        Ok(_) => Ok((StatusCode::OK, Json(json!({"ok": "refreshed"})))),
+        Ok(true) => Ok((StatusCode::OK, Json(json!({"ok": "refreshed"})))),
+        Ok(false) => Ok((StatusCode::OK, Json(json!({"ok": "unchanged"})))),
         Err(e) => {
-            logger::critical(
-                "REFRESH GTFS",
-                &format!("Forbidden access from {}", connect_info.ip()),
-            );
+            logger::critical("REFRESH GTFS", &e);
             Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": e}))))
         }
     }
 }
+
+pub async fn validation(
+    State(app): State<Arc<Store>>,
+    ConnectInfo(connect_info): ConnectInfo<SocketAddr>,
+) -> impl IntoResponse {
+    if connect_info.ip().to_string() != "127.0.0.1" {
+        logger::critical(
+            "VALIDATION",
+            &format!("Forbidden access from {}", connect_info.ip()),
+        );
+        return Err((StatusCode::FORBIDDEN, Json(json!({"error": "Forbidden"}))));
+    }
+
+    let dataset = app.dataset();
+    Ok((StatusCode::OK, Json(json!(dataset.report))))
+}
+
+#[derive(Deserialize)]
+pub struct DiffsQuery {
+    pub limit: Option<i64>,
+}
+
+/// Latest changes of the static GTFS, newest first
+pub async fn diffs(State(app): State<Arc<Store>>, query: Query<DiffsQuery>) -> impl IntoResponse {
+    let limit = query.limit.unwrap_or(10).clamp(1, 100);
+    match app.gtfs_diffs(limit).await {
+        Ok(diffs) => {
+            let val = diffs
+                .iter()
+                .flat_map(|diff| serde_json::from_str::<Value>(diff).ok())
+                .collect::<Vec<Value>>();
+            Ok((StatusCode::OK, Json(json!(val))))
+        }
+        Err(e) => {
+            logger::critical("GTFS DIFF", &e);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "Internal error"})),
+            ))
+        }
+    }
+}
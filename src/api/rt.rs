@@ -1,9 +1,18 @@
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde::Deserialize;
 use serde_json::{json, Value};
 
-use crate::store::Store;
+use crate::store::{Alert, Store, TripUpdate};
 
 //axum erro
 pub async fn avg_speed(State(app): State<Arc<Store>>) -> impl IntoResponse {
@@ -24,3 +33,111 @@ pub async fn avg_speed(State(app): State<Arc<Store>>) -> impl IntoResponse {
         .collect::<Vec<Value>>();
     (StatusCode::OK, Json(val))
 }
+
+#[derive(Deserialize)]
+pub struct TripUpdatesQuery {
+    pub trip_id: Option<String>,
+    pub route_id: Option<String>,
+}
+
+pub async fn trip_updates(
+    State(app): State<Arc<Store>>,
+    query: Query<TripUpdatesQuery>,
+) -> impl IntoResponse {
+    let trip_updates = app.get_trip_updates();
+    let val = trip_updates
+        .iter()
+        .filter(|x| {
+            let value = x.value();
+            query.trip_id.as_ref().is_none_or(|e| *e == value.trip_id)
+                && query
+                    .route_id
+                    .as_ref()
+                    .is_none_or(|e| Some(e) == value.route_id.as_ref())
+        })
+        .map(|x| x.value().clone())
+        .collect::<Vec<TripUpdate>>();
+    (StatusCode::OK, Json(val))
+}
+
+#[derive(Deserialize)]
+pub struct AlertsQuery {
+    pub all: Option<bool>,
+}
+
+pub async fn alerts(State(app): State<Arc<Store>>, query: Query<AlertsQuery>) -> impl IntoResponse {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|e| e.as_secs())
+        .unwrap_or(0);
+
+    let all = query.all.unwrap_or(false);
+    let val = app
+        .alerts()
+        .await
+        .into_iter()
+        .filter(|alert| all || alert.is_active(now))
+        .collect::<Vec<Alert>>();
+    (StatusCode::OK, Json(val))
+}
+
+pub async fn detours(State(app): State<Arc<Store>>) -> impl IntoResponse {
+    let vehicles_state = app.get_vehicles_state();
+    let val = vehicles_state
+        .iter()
+        .filter_map(|x| {
+            let value = x.value().read().unwrap();
+            if !value.is_out {
+                return None;
+            }
+
+            Some(json!({
+                "bus": x.key(),
+                "trip_id": value.trip_id,
+                "out_since": value.out_since,
+                "last_seen": value.timestamp,
+            }))
+        })
+        .collect::<Vec<Value>>();
+    (StatusCode::OK, Json(val))
+}
+
+pub async fn vehicle_predictions(
+    State(app): State<Arc<Store>>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match app.get_predictions().get(&id) {
+        Some(prediction) => Ok((StatusCode::OK, Json(prediction.clone()))),
+        None => Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "No prediction for this vehicle"})),
+        )),
+    }
+}
+
+pub async fn stop_predictions(
+    State(app): State<Arc<Store>>,
+    Path(stop_id): Path<String>,
+) -> impl IntoResponse {
+    let predictions = app.get_predictions();
+    let mut val = predictions
+        .iter()
+        .flat_map(|x| {
+            let value = x.value();
+            let stop = value.stops.iter().find(|stop| stop.stop_id == stop_id)?;
+            Some((
+                stop.predicted_arrival,
+                json!({
+                    "bus": value.bus,
+                    "trip_id": value.trip_id,
+                    "line": value.line,
+                    "prediction": stop,
+                }),
+            ))
+        })
+        .collect::<Vec<(i64, Value)>>();
+
+    val.sort_by_key(|(predicted_arrival, _)| *predicted_arrival);
+    let val = val.into_iter().map(|(_, e)| e).collect::<Vec<Value>>();
+    (StatusCode::OK, Json(val))
+}
@@ -0,0 +1,79 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::NaiveDate;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    calendar::{ScheduledTrip, ServiceSummary},
+    store::Store,
+    timezone, utils,
+};
+
+#[derive(Deserialize)]
+pub struct ScheduleQuery {
+    /// Service date (`YYYYMMDD`), today in the agency time zone by default
+    pub date: Option<String>,
+    pub route_id: Option<String>,
+}
+
+fn service_date(app: &Store, query: &ScheduleQuery) -> Option<NaiveDate> {
+    match &query.date {
+        Some(date) => utils::parse_date(date),
+        None => Some(timezone::date(
+            &app.dataset().timezone,
+            chrono::Utc::now().timestamp(),
+        )),
+    }
+}
+
+/// Trips expected on a service date against the ones observed, per route
+pub async fn summary(
+    State(app): State<Arc<Store>>,
+    query: Query<ScheduleQuery>,
+) -> impl IntoResponse {
+    let date = match service_date(&app, &query) {
+        Some(e) => e,
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Invalid date, expected YYYYMMDD"})),
+            ))
+        }
+    };
+
+    let observed = app.observed_trips(&date.format("%Y%m%d").to_string()).await;
+    let summary = ServiceSummary::build(&app.dataset().gtfs, date, &observed);
+    Ok((StatusCode::OK, Json(json!(summary))))
+}
+
+/// Trips scheduled on a service date, optionally of a single route
+pub async fn trips(
+    State(app): State<Arc<Store>>,
+    query: Query<ScheduleQuery>,
+) -> impl IntoResponse {
+    let date = match service_date(&app, &query) {
+        Some(e) => e,
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Invalid date, expected YYYYMMDD"})),
+            ))
+        }
+    };
+
+    let observed = app.observed_trips(&date.format("%Y%m%d").to_string()).await;
+    let trips = ScheduledTrip::list(
+        &app.dataset().gtfs,
+        date,
+        query.route_id.as_deref(),
+        &observed,
+    );
+    Ok((StatusCode::OK, Json(json!(trips))))
+}
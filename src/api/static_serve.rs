@@ -18,3 +18,14 @@ pub async fn serve(State(app): State<Arc<Store>>) -> impl IntoResponse {
         Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, "Error building response")),
     }
 }
+
+pub async fn serve_enriched(State(app): State<Arc<Store>>) -> impl IntoResponse {
+    let datas = app.enriched_data().await;
+    match Response::builder()
+        .header("Content-Type", "application/x-protobuf")
+        .body(Body::from(datas))
+    {
+        Ok(response) => Ok(response),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, "Error building response")),
+    }
+}
@@ -0,0 +1,11 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use serde_json::json;
+
+use crate::store::Store;
+
+pub async fn status(State(app): State<Arc<Store>>) -> impl IntoResponse {
+    let status = app.fetch_status().await;
+    (StatusCode::OK, Json(json!(status)))
+}
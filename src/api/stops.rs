@@ -0,0 +1,124 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{departures, quadtree::Extent, store::Store};
+
+const DEFAULT_LIMIT: usize = 10;
+const MAX_LIMIT: usize = 100;
+
+#[derive(Deserialize)]
+pub struct StopsQuery {
+    /// min_lon,min_lat,max_lon,max_lat
+    pub bbox: String,
+}
+
+pub async fn stops(State(app): State<Arc<Store>>, query: Query<StopsQuery>) -> impl IntoResponse {
+    let bbox = match query.bbox.parse::<Extent>() {
+        Ok(e) => e,
+        Err(_) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Invalid bbox, expected min_lon,min_lat,max_lon,max_lat"})),
+            ))
+        }
+    };
+
+    let dataset = app.dataset();
+    let (gtfs, index) = (&dataset.gtfs, &dataset.index);
+
+    let val = index
+        .find_stops(gtfs, &bbox)
+        .iter()
+        .map(|stop| {
+            json!({
+                "stop_id": stop.id,
+                "stop_name": stop.name,
+                "latitude": stop.latitude,
+                "longitude": stop.longitude,
+            })
+        })
+        .collect::<Vec<Value>>();
+
+    Ok((StatusCode::OK, Json(val)))
+}
+
+#[derive(Deserialize)]
+pub struct NearestQuery {
+    pub lat: f64,
+    pub lon: f64,
+    pub limit: Option<usize>,
+}
+
+pub async fn nearest(
+    State(app): State<Arc<Store>>,
+    query: Query<NearestQuery>,
+) -> impl IntoResponse {
+    let dataset = app.dataset();
+    let (gtfs, index) = (&dataset.gtfs, &dataset.index);
+
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let val = index
+        .nearest_stops(gtfs, query.lat, query.lon, limit)
+        .iter()
+        .map(|(stop, distance)| {
+            json!({
+                "stop_id": stop.id,
+                "stop_name": stop.name,
+                "latitude": stop.latitude,
+                "longitude": stop.longitude,
+                "distance": distance,
+            })
+        })
+        .collect::<Vec<Value>>();
+
+    (StatusCode::OK, Json(val))
+}
+
+#[derive(Deserialize)]
+pub struct DeparturesQuery {
+    pub limit: Option<usize>,
+}
+
+pub async fn departures(
+    State(app): State<Arc<Store>>,
+    Path(stop_id): Path<String>,
+    query: Query<DeparturesQuery>,
+) -> impl IntoResponse {
+    let dataset = app.dataset();
+    let gtfs = &dataset.gtfs;
+    let stop = match gtfs.stops.get(&stop_id) {
+        Some(e) => e,
+        None => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                Json(json!({"error": "Unknown stop"})),
+            ))
+        }
+    };
+
+    let departures = departures::next_departures(
+        &dataset,
+        &app.get_predictions(),
+        &app.get_trip_updates(),
+        &stop_id,
+        chrono::Utc::now(),
+        query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT),
+    );
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "stop_id": stop.id,
+            "stop_name": stop.name,
+            "departures": departures,
+        })),
+    ))
+}
@@ -0,0 +1,79 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    quadtree::Extent,
+    store::{Bus, Store},
+};
+
+#[derive(Deserialize)]
+pub struct VehiclesQuery {
+    pub line: Option<String>,
+    pub line_id: Option<String>,
+    pub agency_id: Option<String>,
+    pub trip_id: Option<String>,
+    pub min_delay: Option<f64>,
+    /// min_lon,min_lat,max_lon,max_lat
+    pub bbox: Option<String>,
+}
+
+pub async fn vehicles(
+    State(app): State<Arc<Store>>,
+    query: Query<VehiclesQuery>,
+) -> impl IntoResponse {
+    let bbox = match query.bbox.as_deref().map(str::parse::<Extent>) {
+        Some(Err(_)) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Invalid bbox, expected min_lon,min_lat,max_lon,max_lat"})),
+            ))
+        }
+        Some(Ok(bbox)) => Some(bbox),
+        None => None,
+    };
+
+    let vehicles = app.vehicles().await;
+    let candidates: Vec<&Bus> = match &bbox {
+        Some(bbox) => app
+            .find_vehicles(bbox)
+            .await
+            .iter()
+            .filter_map(|id| vehicles.get(id))
+            .collect(),
+        None => vehicles.buses.iter().collect(),
+    };
+
+    let val = candidates
+        .into_iter()
+        .filter(|bus| {
+            query.line.as_ref().is_none_or(|e| *e == bus.line)
+                && query.line_id.as_ref().is_none_or(|e| *e == bus.line_id)
+                && query.agency_id.as_ref().is_none_or(|e| *e == bus.agency_id)
+                && query.trip_id.as_ref().is_none_or(|e| *e == bus.trip_id)
+                && query
+                    .min_delay
+                    .is_none_or(|e| bus.delay.is_some_and(|delay| delay >= e))
+        })
+        .collect::<Vec<&Bus>>();
+
+    Ok((StatusCode::OK, Json(json!(val))))
+}
+
+pub async fn vehicle(State(app): State<Arc<Store>>, Path(id): Path<String>) -> impl IntoResponse {
+    let vehicles = app.vehicles().await;
+    match vehicles.get(&id) {
+        Some(bus) => Ok((StatusCode::OK, Json(json!(bus)))),
+        None => Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "Unknown vehicle"})),
+        )),
+    }
+}
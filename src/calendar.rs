@@ -0,0 +1,140 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use chrono::{Datelike, NaiveDate, Weekday};
+use gtfs_structures::{Exception, Gtfs, Trip};
+use serde::Serialize;
+
+/// Whether a service runs on a date, calendar_dates.txt exceptions taking
+/// precedence over the weekly pattern of calendar.txt
+pub fn is_active(gtfs: &Gtfs, service_id: &str, date: NaiveDate) -> bool {
+    if let Some(dates) = gtfs.calendar_dates.get(service_id) {
+        if let Some(exception) = dates.iter().find(|e| e.date == date) {
+            return exception.exception_type == Exception::Added;
+        }
+    }
+
+    let calendar = match gtfs.calendar.get(service_id) {
+        Some(e) => e,
+        None => return false,
+    };
+
+    if date < calendar.start_date || date > calendar.end_date {
+        return false;
+    }
+
+    match date.weekday() {
+        Weekday::Mon => calendar.monday,
+        Weekday::Tue => calendar.tuesday,
+        Weekday::Wed => calendar.wednesday,
+        Weekday::Thu => calendar.thursday,
+        Weekday::Fri => calendar.friday,
+        Weekday::Sat => calendar.saturday,
+        Weekday::Sun => calendar.sunday,
+    }
+}
+
+/// Trips running on a service date
+pub fn scheduled_trips(gtfs: &Gtfs, date: NaiveDate) -> Vec<&Trip> {
+    let mut services: HashMap<&str, bool> = HashMap::new();
+    gtfs.trips
+        .values()
+        .filter(|trip| {
+            *services
+                .entry(trip.service_id.as_str())
+                .or_insert_with(|| is_active(gtfs, &trip.service_id, date))
+        })
+        .collect()
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ScheduledTrip {
+    pub trip_id: String,
+    pub route_id: String,
+    pub service_id: String,
+    pub headsign: Option<String>,
+    /// First departure, in seconds since the start of the service day
+    pub start_time: Option<u32>,
+    /// Whether a vehicle was seen on it
+    pub observed: bool,
+}
+
+impl ScheduledTrip {
+    /// Trips of a service date sorted by first departure, optionally of a
+    /// single route
+    pub fn list(
+        gtfs: &Gtfs,
+        date: NaiveDate,
+        route_id: Option<&str>,
+        observed: &HashSet<String>,
+    ) -> Vec<Self> {
+        let mut trips = scheduled_trips(gtfs, date)
+            .into_iter()
+            .filter(|trip| route_id.is_none_or(|route_id| trip.route_id == route_id))
+            .map(|trip| Self {
+                trip_id: trip.id.clone(),
+                route_id: trip.route_id.clone(),
+                service_id: trip.service_id.clone(),
+                headsign: trip.trip_headsign.clone(),
+                start_time: trip
+                    .stop_times
+                    .first()
+                    .and_then(|e| e.departure_time.or(e.arrival_time)),
+                observed: observed.contains(&trip.id),
+            })
+            .collect::<Vec<Self>>();
+
+        trips.sort_by(|a, b| (a.start_time, &a.trip_id).cmp(&(b.start_time, &b.trip_id)));
+        trips
+    }
+}
+
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct TripCounts {
+    pub expected: usize,
+    pub observed: usize,
+}
+
+/// Trips scheduled on a service date against the ones vehicles were seen on
+#[derive(Serialize, Debug, Clone)]
+pub struct ServiceSummary {
+    pub date: String,
+    pub expected: usize,
+    pub observed: usize,
+    /// Trips seen on that date although they are not scheduled on it
+    pub unscheduled: Vec<String>,
+    pub routes: BTreeMap<String, TripCounts>,
+}
+
+impl ServiceSummary {
+    pub fn build(gtfs: &Gtfs, date: NaiveDate, observed: &HashSet<String>) -> Self {
+        let trips = scheduled_trips(gtfs, date);
+
+        let mut routes: BTreeMap<String, TripCounts> = BTreeMap::new();
+        for trip in &trips {
+            let counts = routes.entry(trip.route_id.clone()).or_default();
+            counts.expected += 1;
+            if observed.contains(&trip.id) {
+                counts.observed += 1;
+            }
+        }
+
+        let scheduled = trips
+            .iter()
+            .map(|trip| trip.id.as_str())
+            .collect::<HashSet<&str>>();
+        let mut unscheduled = observed
+            .iter()
+            .filter(|trip_id| !scheduled.contains(trip_id.as_str()))
+            .cloned()
+            .collect::<Vec<String>>();
+        unscheduled.sort_unstable();
+
+        Self {
+            date: date.format("%Y%m%d").to_string(),
+            expected: trips.len(),
+            observed: routes.values().map(|e| e.observed).sum(),
+            unscheduled,
+            routes,
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::HashSet;
+
+use serde::Deserialize;
+
+/// One operator: its realtime feed(s) and static GTFS
+///
+/// The namespace keys everything coming from the feed (store, routes,
+/// database rows) so ids from different operators never collide
+#[derive(Debug, Clone, Deserialize)]
+pub struct FeedConfig {
+    #[serde(default)]
+    pub namespace: String,
+    pub realtime_urls: Vec<String>,
+    #[serde(default = "default_gtfs_path")]
+    pub gtfs_path: String,
+    /// GTFS zip downloaded instead of reading `gtfs_path`
+    #[serde(default)]
+    pub gtfs_url: Option<String>,
+    /// Seconds between two downloads of `gtfs_url`
+    #[serde(default = "default_gtfs_refresh_interval")]
+    pub gtfs_refresh_interval: u64,
+    /// Seconds between two fetches
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval: f64,
+    /// Directory where every fetched feed is archived
+    #[serde(default)]
+    pub record_dir: Option<String>,
+}
+
+fn default_gtfs_path() -> String {
+    "gtfs".to_string()
+}
+
+fn default_gtfs_refresh_interval() -> u64 {
+    3600
+}
+
+fn default_poll_interval() -> f64 {
+    5.0
+}
+
+impl FeedConfig {
+    /// Single feed configured through `API_URL`, served at the root
+    pub fn legacy(api_url: String) -> Self {
+        Self {
+            namespace: String::new(),
+            realtime_urls: vec![api_url],
+            gtfs_path: default_gtfs_path(),
+            gtfs_url: None,
+            gtfs_refresh_interval: default_gtfs_refresh_interval(),
+            poll_interval: default_poll_interval(),
+            record_dir: None,
+        }
+    }
+}
+
+/// Read and check a JSON array of feeds
+pub fn load_feeds(path: &str) -> Result<Vec<FeedConfig>, String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("Error reading feeds config {}: {}", path, e))?;
+    let feeds: Vec<FeedConfig> = serde_json::from_str(&content)
+        .map_err(|e| format!("Error parsing feeds config {}: {}", path, e))?;
+
+    if feeds.is_empty() {
+        return Err(format!("No feed in {}", path));
+    }
+
+    let mut namespaces = HashSet::new();
+    for feed in &feeds {
+        if !feed
+            .namespace
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!("Invalid namespace: {}", feed.namespace));
+        }
+
+        if !namespaces.insert(feed.namespace.as_str()) {
+            return Err(format!("Duplicate namespace: {}", feed.namespace));
+        }
+
+        if feed.realtime_urls.is_empty() {
+            return Err(format!("No realtime url for feed {}", feed.namespace));
+        }
+
+        if feed.gtfs_refresh_interval == 0 {
+            return Err(format!(
+                "Invalid GTFS refresh interval for feed {}",
+                feed.namespace
+            ));
+        }
+
+        if !feed.poll_interval.is_finite() || feed.poll_interval <= 0.0 {
+            return Err(format!("Invalid poll interval for feed {}", feed.namespace));
+        }
+    }
+
+    Ok(feeds)
+}
@@ -3,6 +3,7 @@
 use sqlx::{postgres::PgPoolOptions, PgPool, Result};
 use std::{collections::VecDeque, sync::Arc};
 
+use crate::diff::GtfsDiff;
 use crate::store::Bus;
 
 #[derive(Debug, Clone)]
@@ -26,7 +27,7 @@ impl Db {
 }
 
 impl Db {
-    pub async fn insert_buses(&self, buses: &VecDeque<Bus>) -> Result<()> {
+    pub async fn insert_buses(&self, feed: &str, buses: &VecDeque<Bus>) -> Result<()> {
         // Start a new transaction
         let mut transaction = self.pool.begin().await?;
 
@@ -34,9 +35,9 @@ impl Db {
         for bus in buses {
             sqlx::query!(
                 "INSERT INTO transport_data (timestamp, id, line, line_id, trip_id, agency_id, latitude, longitude, speed, 
-                 average_speed, next_stop, theorical_stop, delay)
-                 VALUES (TO_TIMESTAMP($1), $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
-                    ON CONFLICT (timestamp, id) DO UPDATE SET 
+                 average_speed, next_stop, theorical_stop, delay, is_out, out_since, rejoined_at, feed)
+                 VALUES (TO_TIMESTAMP($1), $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, TO_TIMESTAMP($15), TO_TIMESTAMP($16), $17)
+                    ON CONFLICT (feed, id, timestamp) DO UPDATE SET 
                         line = EXCLUDED.line,
                         line_id = EXCLUDED.line_id,
                         trip_id = EXCLUDED.trip_id,
@@ -47,7 +48,10 @@ impl Db {
                         average_speed = EXCLUDED.average_speed,
                         next_stop = EXCLUDED.next_stop,
                         theorical_stop = EXCLUDED.theorical_stop,
-                        delay = EXCLUDED.delay
+                        delay = EXCLUDED.delay,
+                        is_out = EXCLUDED.is_out,
+                        out_since = EXCLUDED.out_since,
+                        rejoined_at = EXCLUDED.rejoined_at
                  ",
                 bus.timestamp as i64,  // casting u64 to i64 for sql compatibility
                 bus.id,
@@ -61,7 +65,11 @@ impl Db {
                 bus.average_speed,
                 bus.next_stop as i32,     
                 bus.theorical_stop as i32, 
-                bus.delay
+                bus.delay,
+                bus.is_out,
+                bus.out_since.map(|e| e as f64),
+                bus.rejoined_at.map(|e| e as f64),
+                feed
             )
             .execute(&mut *transaction)
             .await?;
@@ -71,4 +79,79 @@ impl Db {
         transaction.commit().await?;
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Mean time between passing two consecutive stops, per (trip, next stop)
+    pub async fn segment_run_times(
+        &self,
+        feed: &str,
+        days: i32,
+    ) -> Result<Vec<(String, usize, f64, i64)>> {
+        let rows = sqlx::query!(
+            "SELECT trip_id, next_stop, AVG(run_time) AS run_time, COUNT(*) AS count
+                 FROM (
+                    SELECT trip_id, next_stop,
+                        EXTRACT(EPOCH FROM (LEAD(reached) OVER w - reached))::FLOAT8 AS run_time,
+                        LEAD(next_stop) OVER w AS following
+                    FROM (
+                        SELECT id, trip_id, next_stop, DATE(timestamp) AS day, MIN(timestamp) AS reached
+                        FROM transport_data
+                        WHERE timestamp >= NOW() - make_interval(days => $1)
+                            AND feed = $2
+                            AND trip_id IS NOT NULL
+                            AND next_stop IS NOT NULL
+                        GROUP BY id, trip_id, next_stop, DATE(timestamp)
+                    ) passages
+                    WINDOW w AS (PARTITION BY id, trip_id, day ORDER BY next_stop)
+                 ) runs
+                 WHERE following = next_stop + 1 AND run_time > 0
+                 GROUP BY trip_id, next_stop
+            ",
+            days,
+            feed
+        )
+        .fetch_all(&*self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .flat_map(|row| {
+                Some((
+                    row.trip_id?,
+                    row.next_stop? as usize,
+                    row.run_time?,
+                    row.count?,
+                ))
+            })
+            .collect())
+    }
+
+    pub async fn insert_gtfs_diff(&self, feed: &str, diff: &GtfsDiff) -> Result<()> {
+        let json = serde_json::to_string(diff).unwrap_or_default();
+        sqlx::query!(
+            "INSERT INTO gtfs_diffs (generated_at, feed, from_version, to_version, diff)
+                 VALUES (TO_TIMESTAMP($1), $2, $3, $4, $5)",
+            diff.generated_at as f64,
+            feed,
+            diff.from_version,
+            diff.to_version,
+            json
+        )
+        .execute(&*self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Latest diffs of the feed (JSON), newest first
+    pub async fn gtfs_diffs(&self, feed: &str, limit: i64) -> Result<Vec<String>> {
+        let rows = sqlx::query!(
+            "SELECT diff FROM gtfs_diffs WHERE feed = $1 ORDER BY generated_at DESC LIMIT $2",
+            feed,
+            limit
+        )
+        .fetch_all(&*self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| row.diff).collect())
+    }
+}
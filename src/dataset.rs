@@ -0,0 +1,292 @@
+use std::{io::Cursor, sync::Mutex};
+
+use chrono_tz::Tz;
+use gtfs_structures::{Gtfs, GtfsReader};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::FeedConfig,
+    http::{HttpClient, HttpConfig, Validators},
+    index::GtfsIndex,
+    logger,
+    validation::ValidationReport,
+};
+
+/// Static GTFS with the indexes built from it, replaced as a whole so they
+/// always match
+#[derive(Default)]
+pub struct Dataset {
+    pub gtfs: Gtfs,
+    pub index: GtfsIndex,
+    /// Sha256 of the zip, for datasets downloaded from a url
+    pub hash: Option<String>,
+    pub report: ValidationReport,
+    /// Time zone of the first agency, the one stop times are expressed in
+    pub timezone: Tz,
+}
+
+impl Dataset {
+    pub fn load(path: &str) -> Result<Self, String> {
+        logger::fine("GTFS", &format!("Loading {}", path));
+        let gtfs = Self::reader()
+            .read_from_path(path)
+            .map_err(|e| format!("Error loading GTFS {}: {}", path, e))?;
+
+        Self::build(gtfs, None)
+    }
+
+    pub fn from_zip(zip: Vec<u8>, hash: String) -> Result<Self, String> {
+        let gtfs = Self::reader()
+            .raw()
+            .read_from_reader(Cursor::new(zip))
+            .and_then(Gtfs::try_from)
+            .map_err(|e| format!("Error loading GTFS: {}", e))?;
+
+        Self::build(gtfs, Some(hash))
+    }
+
+    fn reader() -> GtfsReader {
+        GtfsReader::default()
+            .read_stop_times(true)
+            .read_shapes(true)
+    }
+
+    fn build(gtfs: Gtfs, hash: Option<String>) -> Result<Self, String> {
+        if gtfs.routes.is_empty() || gtfs.trips.is_empty() || gtfs.stops.is_empty() {
+            return Err("GTFS has no routes, trips or stops".to_string());
+        }
+
+        //Stop times read in another zone would all be off by its offset
+        let agency = gtfs.agencies.first().ok_or("GTFS has no agency")?;
+        let timezone = agency
+            .timezone
+            .parse::<Tz>()
+            .map_err(|e| format!("Invalid agency_timezone {}: {}", agency.timezone, e))?;
+
+        logger::fine("GTFS", "Loaded GTFS");
+
+        let index = GtfsIndex::build(&gtfs);
+        let report = ValidationReport::build(&gtfs, &index);
+        report.log();
+
+        Ok(Self {
+            gtfs,
+            index,
+            hash,
+            report,
+            timezone,
+        })
+    }
+
+    /// `feed_version` of feed_info.txt
+    pub fn version(&self) -> Option<&str> {
+        self.gtfs.feed_info.first()?.version.as_deref()
+    }
+}
+
+/// Where the static GTFS of a feed is read from
+pub enum GtfsSource {
+    /// Directory or zip on disk, read again on every reload
+    Path(String),
+    /// Zip downloaded on every reload, loaded only if it changed
+    Url {
+        url: String,
+        client: HttpClient,
+        /// Validators of the last loaded zip
+        validators: Mutex<Option<Validators>>,
+    },
+}
+
+impl GtfsSource {
+    pub fn new(feed: &FeedConfig, http: HttpConfig) -> Result<Self, String> {
+        match &feed.gtfs_url {
+            Some(url) => Ok(Self::Url {
+                url: url.clone(),
+                client: HttpClient::new(http)?,
+                validators: Mutex::new(None),
+            }),
+            None => Ok(Self::Path(feed.gtfs_path.clone())),
+        }
+    }
+
+    /// New dataset, `None` if it is the same as the current one
+    pub async fn load(&self, current: &Dataset) -> Result<Option<Dataset>, String> {
+        let (url, client, validators) = match self {
+            Self::Path(path) => {
+                let path = path.clone();
+                return tokio::task::spawn_blocking(move || Dataset::load(&path))
+                    .await
+                    .map_err(|e| format!("Error loading GTFS: {}", e))?
+                    .map(Some);
+            }
+            Self::Url {
+                url,
+                client,
+                validators,
+            } => (url, client, validators),
+        };
+
+        logger::fine("GTFS", &format!("Downloading {}", url));
+        let previous = validators.lock().unwrap().clone();
+        let resp = match client.get(url, previous.as_ref()).await {
+            Ok(Some(resp)) => resp,
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(format!("Error downloading GTFS {}: {}", url, e)),
+        };
+
+        let hash = format!("{:x}", Sha256::digest(&resp.body));
+        if current.hash.as_ref() == Some(&hash) {
+            *validators.lock().unwrap() = Some(resp.validators);
+            return Ok(None);
+        }
+
+        let dataset = tokio::task::spawn_blocking(move || Dataset::from_zip(resp.body, hash))
+            .await
+            .map_err(|e| format!("Error loading GTFS: {}", e))??;
+
+        if current.hash.is_some() && dataset.version().is_some() {
+            match dataset.version() == current.version() {
+                true => logger::warn(
+                    "GTFS",
+                    "GTFS content changed but its feed_version is the same",
+                ),
+                false => logger::info(
+                    "GTFS",
+                    &format!(
+                        "GTFS version {} -> {}",
+                        current.version().unwrap_or("none"),
+                        dataset.version().unwrap_or("none")
+                    ),
+                ),
+            }
+        }
+
+        *validators.lock().unwrap() = Some(resp.validators);
+        Ok(Some(dataset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use axum::{
+        extract::State,
+        http::{header, HeaderMap, StatusCode},
+        response::IntoResponse,
+        routing::get,
+        Router,
+    };
+
+    use super::*;
+
+    const ZIP: &[u8] = include_bytes!("../fixtures/gtfs.zip");
+
+    /// Requests answered with the zip and with 304
+    #[derive(Default)]
+    struct Server {
+        /// Whether If-None-Match is honoured
+        conditional: bool,
+        full: AtomicUsize,
+        not_modified: AtomicUsize,
+    }
+
+    async fn respond(State(server): State<Arc<Server>>, headers: HeaderMap) -> impl IntoResponse {
+        let etag = [(header::ETAG, "\"gtfs\"")];
+        if server.conditional
+            && headers
+                .get(header::IF_NONE_MATCH)
+                .is_some_and(|e| e == "\"gtfs\"")
+        {
+            server.not_modified.fetch_add(1, Ordering::SeqCst);
+            return (StatusCode::NOT_MODIFIED, etag, Vec::new());
+        }
+
+        server.full.fetch_add(1, Ordering::SeqCst);
+        (StatusCode::OK, etag, ZIP.to_vec())
+    }
+
+    async fn serve(conditional: bool) -> (GtfsSource, Arc<Server>) {
+        let server = Arc::new(Server {
+            conditional,
+            ..Default::default()
+        });
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/gtfs.zip", listener.local_addr().unwrap());
+        let app = Router::new()
+            .route("/gtfs.zip", get(respond))
+            .with_state(server.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let feed = FeedConfig {
+            gtfs_url: Some(url),
+            ..FeedConfig::legacy(String::new())
+        };
+        (
+            GtfsSource::new(&feed, HttpConfig::default()).unwrap(),
+            server,
+        )
+    }
+
+    #[tokio::test]
+    async fn url_is_loaded_once() {
+        let (source, server) = serve(true).await;
+
+        let dataset = source.load(&Dataset::default()).await.unwrap().unwrap();
+        let hash = format!("{:x}", Sha256::digest(ZIP));
+        assert_eq!(dataset.hash, Some(hash));
+        assert!(!dataset.gtfs.trips.is_empty());
+
+        //Conditional GET, the zip isn't downloaded again
+        assert!(source.load(&dataset).await.unwrap().is_none());
+        assert_eq!(server.full.load(Ordering::SeqCst), 1);
+        assert_eq!(server.not_modified.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn same_zip_is_not_loaded_again() {
+        let (source, server) = serve(false).await;
+
+        let dataset = source.load(&Dataset::default()).await.unwrap().unwrap();
+        assert!(source.load(&dataset).await.unwrap().is_none());
+        assert_eq!(server.full.load(Ordering::SeqCst), 2);
+
+        //A dataset of another zip is replaced
+        let other = Dataset {
+            hash: Some("other".to_string()),
+            ..Default::default()
+        };
+        assert!(source.load(&other).await.unwrap().is_some());
+    }
+
+    #[test]
+    fn unknown_time_zone_is_an_error() {
+        let mut gtfs = Dataset::from_zip(ZIP.to_vec(), String::new()).unwrap().gtfs;
+        let mut agency = gtfs.agencies[0].clone();
+        agency.timezone = "Europe/Atlantis".to_string();
+        gtfs.agencies = vec![agency];
+
+        let error = Dataset::build(gtfs, None).err().unwrap();
+        assert!(error.starts_with("Invalid agency_timezone Europe/Atlantis"));
+    }
+
+    #[tokio::test]
+    async fn failed_download_names_url() {
+        let feed = FeedConfig {
+            gtfs_url: Some("http://127.0.0.1:1/gtfs.zip".to_string()),
+            ..FeedConfig::legacy(String::new())
+        };
+        let http = HttpConfig {
+            retries: 0,
+            ..Default::default()
+        };
+        let source = GtfsSource::new(&feed, http).unwrap();
+
+        let error = source.load(&Dataset::default()).await.err().unwrap();
+        assert!(error.starts_with("Error downloading GTFS http://127.0.0.1:1/gtfs.zip"));
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Days, Utc};
+use dashmap::DashMap;
+use serde::Serialize;
+
+use crate::{
+    calendar,
+    dataset::Dataset,
+    frequency,
+    prediction::{StopPrediction, TripPrediction},
+    store::{self, TripUpdates},
+    timezone, utils,
+};
+
+/// How long a late departure is still looked for after its scheduled time (s)
+const LOOKBACK: i64 = 3600;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Departure {
+    pub trip_id: String,
+    /// Service date of the run (`YYYYMMDD`)
+    pub start_date: String,
+    /// Departure of a frequency-based trip (`HH:MM:SS`)
+    pub start_time: Option<String>,
+    pub route_id: String,
+    pub line: Option<String>,
+    pub headsign: Option<String>,
+    pub scheduled: i64,
+    pub predicted: Option<i64>,
+    pub delay: Option<f64>,
+    pub bus: Option<String>,
+    pub realtime: bool,
+    pub canceled: bool,
+}
+
+/// Next departures from a stop, merging the schedule of the current and
+/// previous service days (for trips past midnight) with the predictions
+pub fn next_departures(
+    dataset: &Dataset,
+    predictions: &DashMap<String, TripPrediction>,
+    trip_updates: &TripUpdates,
+    stop_id: &str,
+    now: DateTime<Utc>,
+    limit: usize,
+) -> Vec<Departure> {
+    let (gtfs, timezone) = (&dataset.gtfs, &dataset.timezone);
+    //A trip past midnight may run on two service dates at once
+    let live = predictions
+        .iter()
+        .map(|e| {
+            let run = (
+                e.trip_id.clone(),
+                e.start_date.clone(),
+                e.start_time.clone(),
+            );
+            (run, e.value().clone())
+        })
+        .collect::<HashMap<(String, String, Option<String>), TripPrediction>>();
+
+    let today = timezone::date(timezone, now.timestamp());
+    let service_days = [today, today - Days::new(1)]
+        .map(|date| (date, timezone::service_day_start(timezone, date)));
+
+    let mut departures = Vec::new();
+    for (trip_id, i) in dataset.index.get_stop_times(stop_id) {
+        let trip = match gtfs.trips.get(trip_id.as_ref()) {
+            Some(e) => e,
+            None => continue,
+        };
+
+        let stop_time = &trip.stop_times[*i];
+        let scheduled = match stop_time.departure_time.or(stop_time.arrival_time) {
+            Some(e) => e as i64,
+            None => continue,
+        };
+
+        //A frequency-based trip departs once per headway
+        let instances = match trip.frequencies.is_empty() {
+            true => vec![(None, 0)],
+            false => frequency::departures(trip)
+                .into_iter()
+                .flat_map(|start| {
+                    Some((
+                        Some(utils::format_time(start)),
+                        frequency::offset(trip, start)?,
+                    ))
+                })
+                .collect::<Vec<(Option<String>, i64)>>(),
+        };
+
+        for (start_time, offset) in &instances {
+            for (date, service_day_start) in service_days {
+                let scheduled = service_day_start + scheduled + offset;
+                if scheduled < now.timestamp() - LOOKBACK
+                    || !calendar::is_active(gtfs, &trip.service_id, date)
+                {
+                    continue;
+                }
+
+                //A live trip only reports the stops it has not passed yet
+                let start_date = date.format("%Y%m%d").to_string();
+                let run = (trip.id.clone(), start_date.clone(), start_time.clone());
+                let (bus, prediction) = match live.get(&run) {
+                    Some(live) => match find_stop(&live.stops, stop_time.stop_sequence) {
+                        Some(prediction) => (Some(live.bus.clone()), Some(prediction)),
+                        None => continue,
+                    },
+                    None => (None, None),
+                };
+
+                let predicted = prediction.map(|e| e.predicted_departure);
+                if predicted.unwrap_or(scheduled) < now.timestamp() {
+                    continue;
+                }
+
+                let canceled = store::find_trip_update(
+                    trip_updates,
+                    &trip.id,
+                    Some(&start_date),
+                    start_time.as_deref(),
+                )
+                .is_some_and(|e| e.is_canceled());
+
+                let route = gtfs.routes.get(&trip.route_id);
+                departures.push(Departure {
+                    trip_id: trip.id.clone(),
+                    start_date,
+                    start_time: start_time.clone(),
+                    route_id: trip.route_id.clone(),
+                    line: route.and_then(|e| e.short_name.clone()),
+                    headsign: stop_time
+                        .stop_headsign
+                        .clone()
+                        .or(trip.trip_headsign.clone()),
+                    scheduled,
+                    predicted,
+                    delay: predicted.map(|e| (e - scheduled) as f64),
+                    bus,
+                    realtime: prediction.is_some(),
+                    canceled,
+                });
+            }
+        }
+    }
+
+    departures.sort_by_key(|e| e.predicted.unwrap_or(e.scheduled));
+    departures.truncate(limit);
+    departures
+}
+
+fn find_stop(stops: &[StopPrediction], stop_sequence: u16) -> Option<&StopPrediction> {
+    stops.iter().find(|e| e.stop_sequence == stop_sequence)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::NaiveDate;
+    use chrono_tz::Europe::Brussels;
+    use gtfs_structures::{Calendar, Stop, StopTime, Trip};
+
+    use super::*;
+    use crate::{index::GtfsIndex, store::TripUpdate};
+
+    /// Trip leaving stop "a" at 23:30 and reaching stop "b" at 01:30, every day
+    fn dataset() -> Dataset {
+        let stop_time = |id: &str, sequence: u16, time: u32| StopTime {
+            stop: Arc::new(Stop {
+                id: id.to_string(),
+                ..Default::default()
+            }),
+            stop_sequence: sequence,
+            arrival_time: Some(time),
+            departure_time: Some(time),
+            ..Default::default()
+        };
+        let trip = Trip {
+            id: "night".to_string(),
+            service_id: "service".to_string(),
+            route_id: "route".to_string(),
+            stop_times: vec![stop_time("a", 1, 84600), stop_time("b", 2, 91800)],
+            ..Default::default()
+        };
+
+        let mut dataset = Dataset {
+            timezone: Brussels,
+            ..Default::default()
+        };
+        dataset.gtfs.trips = HashMap::from([("night".to_string(), trip)]);
+        dataset.gtfs.calendar = HashMap::from([(
+            "service".to_string(),
+            Calendar {
+                id: "service".to_string(),
+                monday: true,
+                tuesday: true,
+                wednesday: true,
+                thursday: true,
+                friday: true,
+                saturday: true,
+                sunday: true,
+                start_date: NaiveDate::from_ymd_opt(2026, 1, 1).unwrap(),
+                end_date: NaiveDate::from_ymd_opt(2026, 12, 31).unwrap(),
+            },
+        )]);
+        dataset.index = GtfsIndex::build(&dataset.gtfs);
+        dataset
+    }
+
+    #[test]
+    fn runs_of_two_service_dates_are_told_apart() {
+        let dataset = dataset();
+        //00:15 local on 2026-06-17, the run of the 16th is on its way
+        let now = DateTime::from_timestamp(1781648100, 0).unwrap();
+
+        let predictions = DashMap::new();
+        predictions.insert(
+            "bus".to_string(),
+            TripPrediction {
+                bus: "bus".to_string(),
+                trip_id: "night".to_string(),
+                start_date: "20260616".to_string(),
+                start_time: None,
+                line: String::new(),
+                stops: vec![StopPrediction {
+                    stop_id: "b".to_string(),
+                    stop_sequence: 2,
+                    scheduled_arrival: None,
+                    scheduled_departure: None,
+                    predicted_arrival: 1781652600,
+                    predicted_departure: 1781652600,
+                    delay: 0.0,
+                }],
+            },
+        );
+
+        //The run of the 17th is canceled
+        let canceled = TripUpdate {
+            id: "1".to_string(),
+            trip_id: "night".to_string(),
+            route_id: None,
+            start_date: Some("20260617".to_string()),
+            start_time: None,
+            vehicle_id: None,
+            timestamp: None,
+            delay: None,
+            schedule_relationship: "CANCELED".to_string(),
+            stop_time_updates: vec![],
+        };
+        let trip_updates = TripUpdates::new();
+        trip_updates.insert(
+            ("night".to_string(), canceled.start_date.clone(), None),
+            canceled,
+        );
+
+        let departures = next_departures(&dataset, &predictions, &trip_updates, "b", now, 10);
+        assert_eq!(departures.len(), 2);
+
+        let (previous, current) = (&departures[0], &departures[1]);
+        assert_eq!(previous.start_date, "20260616");
+        assert!(previous.realtime && !previous.canceled);
+        assert_eq!(current.start_date, "20260617");
+        assert!(!current.realtime && current.canceled);
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use gtfs_structures::Gtfs;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{dataset::Dataset, utils::earth_distance};
+
+/// Distance above which a stop counts as moved (m)
+const STOP_MOVED_DISTANCE: f64 = 50.0;
+
+#[derive(Debug, Serialize)]
+pub struct ModifiedRoute {
+    pub route_id: String,
+    /// route.txt columns that changed
+    pub fields: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct RouteChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<ModifiedRoute>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MovedStop {
+    pub stop_id: String,
+    pub distance: f64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct StopChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub moved: Vec<MovedStop>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct TripChanges {
+    pub added: usize,
+    pub removed: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ShapeChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<String>,
+}
+
+/// What changed between two versions of a static GTFS
+#[derive(Debug, Serialize)]
+pub struct GtfsDiff {
+    pub generated_at: i64,
+    pub from_version: Option<String>,
+    pub to_version: Option<String>,
+    pub routes: RouteChanges,
+    pub stops: StopChanges,
+    /// Trips added and removed, per route with changes
+    pub trips: BTreeMap<String, TripChanges>,
+    pub shapes: ShapeChanges,
+}
+
+impl GtfsDiff {
+    pub fn build(old: &Dataset, new: &Dataset) -> Self {
+        Self {
+            generated_at: chrono::Utc::now().timestamp(),
+            from_version: old.version().map(str::to_string),
+            to_version: new.version().map(str::to_string),
+            routes: diff_routes(&old.gtfs, &new.gtfs),
+            stops: diff_stops(&old.gtfs, &new.gtfs),
+            trips: diff_trips(&old.gtfs, &new.gtfs),
+            shapes: diff_shapes(&old.gtfs, &new.gtfs),
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        let (trips_added, trips_removed) =
+            self.trips.values().fold((0, 0), |(added, removed), e| {
+                (added + e.added, removed + e.removed)
+            });
+
+        format!(
+            "routes +{} -{} ~{}, stops +{} -{} moved {}, trips +{} -{}, shapes +{} -{} ~{}",
+            self.routes.added.len(),
+            self.routes.removed.len(),
+            self.routes.modified.len(),
+            self.stops.added.len(),
+            self.stops.removed.len(),
+            self.stops.moved.len(),
+            trips_added,
+            trips_removed,
+            self.shapes.added.len(),
+            self.shapes.removed.len(),
+            self.shapes.modified.len(),
+        )
+    }
+}
+
+/// Keys only in `new` and keys only in `old`, sorted
+fn added_removed<T, U>(
+    old: &HashMap<String, T>,
+    new: &HashMap<String, U>,
+) -> (Vec<String>, Vec<String>) {
+    let mut added = new
+        .keys()
+        .filter(|id| !old.contains_key(*id))
+        .cloned()
+        .collect::<Vec<String>>();
+    let mut removed = old
+        .keys()
+        .filter(|id| !new.contains_key(*id))
+        .cloned()
+        .collect::<Vec<String>>();
+
+    added.sort_unstable();
+    removed.sort_unstable();
+    (added, removed)
+}
+
+fn diff_routes(old: &Gtfs, new: &Gtfs) -> RouteChanges {
+    let (added, removed) = added_removed(&old.routes, &new.routes);
+
+    let mut modified = new
+        .routes
+        .iter()
+        .flat_map(|(id, route)| {
+            let old_route = serde_json::to_value(old.routes.get(id)?).ok()?;
+            let new_route = serde_json::to_value(route).ok()?;
+            let (Value::Object(old_route), Value::Object(new_route)) = (old_route, new_route)
+            else {
+                return None;
+            };
+
+            let fields = new_route
+                .iter()
+                .filter(|(field, value)| old_route.get(*field) != Some(value))
+                .map(|(field, _)| field.clone())
+                .collect::<Vec<String>>();
+
+            match fields.is_empty() {
+                true => None,
+                false => Some(ModifiedRoute {
+                    route_id: id.clone(),
+                    fields,
+                }),
+            }
+        })
+        .collect::<Vec<ModifiedRoute>>();
+    modified.sort_unstable_by(|a, b| a.route_id.cmp(&b.route_id));
+
+    RouteChanges {
+        added,
+        removed,
+        modified,
+    }
+}
+
+fn diff_stops(old: &Gtfs, new: &Gtfs) -> StopChanges {
+    let (added, removed) = added_removed(&old.stops, &new.stops);
+
+    let mut moved = new
+        .stops
+        .iter()
+        .flat_map(|(id, stop)| {
+            let old_stop = old.stops.get(id)?;
+            let distance = earth_distance(
+                (old_stop.latitude?, old_stop.longitude?),
+                (stop.latitude?, stop.longitude?),
+            );
+
+            match distance > STOP_MOVED_DISTANCE {
+                true => Some(MovedStop {
+                    stop_id: id.clone(),
+                    distance,
+                }),
+                false => None,
+            }
+        })
+        .collect::<Vec<MovedStop>>();
+    moved.sort_unstable_by(|a, b| a.stop_id.cmp(&b.stop_id));
+
+    StopChanges {
+        added,
+        removed,
+        moved,
+    }
+}
+
+fn diff_trips(old: &Gtfs, new: &Gtfs) -> BTreeMap<String, TripChanges> {
+    let trip_ids = |gtfs: &Gtfs| {
+        gtfs.trips
+            .values()
+            .map(|trip| (trip.route_id.clone(), trip.id.clone()))
+            .collect::<HashSet<(String, String)>>()
+    };
+    let (old_trips, new_trips) = (trip_ids(old), trip_ids(new));
+
+    let mut changes: BTreeMap<String, TripChanges> = BTreeMap::new();
+    for (route_id, _) in new_trips.difference(&old_trips) {
+        changes.entry(route_id.clone()).or_default().added += 1;
+    }
+    for (route_id, _) in old_trips.difference(&new_trips) {
+        changes.entry(route_id.clone()).or_default().removed += 1;
+    }
+
+    changes
+}
+
+fn diff_shapes(old: &Gtfs, new: &Gtfs) -> ShapeChanges {
+    let (added, removed) = added_removed(&old.shapes, &new.shapes);
+
+    let mut modified = new
+        .shapes
+        .iter()
+        .filter(|(id, shape)| {
+            old.shapes.get(*id).is_some_and(|old_shape| {
+                old_shape.len() != shape.len()
+                    || old_shape
+                        .iter()
+                        .zip(shape.iter())
+                        .any(|(a, b)| a.latitude != b.latitude || a.longitude != b.longitude)
+            })
+        })
+        .map(|(id, _)| id.clone())
+        .collect::<Vec<String>>();
+    modified.sort_unstable();
+
+    ShapeChanges {
+        added,
+        removed,
+        modified,
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::VecDeque;
+
+use chrono::Utc;
+use dashmap::DashMap;
+use gtfs_structures::{Gtfs, StopTime};
+use protobuf::{EnumOrUnknown, MessageField};
+
+use crate::{
+    gtfs_realtime::{
+        feed_header::Incrementality,
+        trip_descriptor,
+        trip_update::{stop_time_update, StopTimeEvent, StopTimeUpdate},
+        vehicle_position::VehicleStopStatus,
+        FeedEntity, FeedHeader, FeedMessage, Position, TripDescriptor, TripUpdate,
+        VehicleDescriptor, VehiclePosition,
+    },
+    prediction::TripPrediction,
+    store::Bus,
+};
+
+const GTFS_REALTIME_VERSION: &str = "2.0";
+/// A vehicle closer than this to its next stop is at it (m)
+const AT_STOP_DISTANCE: f64 = 30.0;
+/// A vehicle closer than this to its next stop is about to reach it (m)
+const INCOMING_DISTANCE: f64 = 200.0;
+
+/// Build a full dataset feed from the processed buses
+///
+/// Every bus gives a VehiclePosition entity, and a TripUpdate entity
+/// when its delay is known and predictions exist for its trip
+pub fn build_feed(
+    buses: &VecDeque<Bus>,
+    gtfs: &Gtfs,
+    predictions: &DashMap<String, TripPrediction>,
+) -> FeedMessage {
+    let mut header = FeedHeader::new();
+    header.gtfs_realtime_version = Some(GTFS_REALTIME_VERSION.to_string());
+    header.incrementality = Some(EnumOrUnknown::new(Incrementality::FULL_DATASET));
+    header.timestamp = Some(Utc::now().timestamp() as u64);
+
+    let mut message = FeedMessage::new();
+    message.header = MessageField::some(header);
+
+    for bus in buses {
+        let stops = gtfs
+            .trips
+            .get(&bus.trip_id)
+            .map(|trip| trip.stop_times.as_slice());
+
+        message.entity.push(vehicle_entity(bus, stops));
+        if bus.delay.is_none() {
+            continue;
+        }
+        if let Some(prediction) = predictions.get(&bus.id) {
+            if let Some(entity) = trip_update_entity(bus, &prediction) {
+                message.entity.push(entity);
+            }
+        }
+    }
+
+    message
+}
+
+fn trip_descriptor(bus: &Bus) -> TripDescriptor {
+    let mut trip = TripDescriptor::new();
+    if bus.trip_id != "?" {
+        trip.trip_id = Some(bus.trip_id.clone());
+    }
+    if bus.line_id != "?" {
+        trip.route_id = Some(bus.line_id.clone());
+    }
+    trip.start_date = bus.start_date.clone();
+    trip.start_time = bus.start_time.clone();
+    trip
+}
+
+fn vehicle_descriptor(bus: &Bus) -> VehicleDescriptor {
+    let mut vehicle = VehicleDescriptor::new();
+    vehicle.id = Some(bus.id.clone());
+    vehicle
+}
+
+fn vehicle_entity(bus: &Bus, stops: Option<&[StopTime]>) -> FeedEntity {
+    let mut position = Position::new();
+    position.latitude = Some(bus.latitude);
+    position.longitude = Some(bus.longitude);
+    position.speed = Some(bus.speed);
+
+    let mut vehicle = VehiclePosition::new();
+    vehicle.trip = MessageField::some(trip_descriptor(bus));
+    vehicle.vehicle = MessageField::some(vehicle_descriptor(bus));
+    vehicle.position = MessageField::some(position);
+    vehicle.timestamp = Some(bus.timestamp);
+    if let Some(stop) = stops.and_then(|stops| stops.get(bus.next_stop)) {
+        vehicle.current_stop_sequence = Some(stop.stop_sequence as u32);
+        vehicle.stop_id = Some(stop.stop.id.clone());
+        vehicle.current_status = Some(EnumOrUnknown::new(stop_status(bus)));
+    }
+
+    let mut entity = FeedEntity::new();
+    entity.id = Some(format!("vehicle-{}", bus.id));
+    entity.vehicle = MessageField::some(vehicle);
+    entity
+}
+
+/// Where the vehicle is relative to its next stop, from the distance left
+/// to it
+fn stop_status(bus: &Bus) -> VehicleStopStatus {
+    if bus.remaining_distance <= AT_STOP_DISTANCE {
+        VehicleStopStatus::STOPPED_AT
+    } else if bus.remaining_distance <= INCOMING_DISTANCE {
+        VehicleStopStatus::INCOMING_AT
+    } else {
+        VehicleStopStatus::IN_TRANSIT_TO
+    }
+}
+
+/// Predictions for the remaining stops of the trip, as computed by the
+/// propagation model from the delay at the next stop
+///
+/// A vehicle already at its next stop arrived there at its last position
+fn trip_update_entity(bus: &Bus, prediction: &TripPrediction) -> Option<FeedEntity> {
+    let delay = bus.delay?;
+    if prediction.stops.is_empty() {
+        return None;
+    }
+
+    let stopped = bus.remaining_distance <= AT_STOP_DISTANCE;
+    let stop_time_update = prediction
+        .stops
+        .iter()
+        .enumerate()
+        .map(|(i, stop)| {
+            let mut update = StopTimeUpdate::new();
+            update.stop_sequence = Some(stop.stop_sequence as u32);
+            update.stop_id = Some(stop.stop_id.clone());
+            update.schedule_relationship = Some(EnumOrUnknown::new(
+                stop_time_update::ScheduleRelationship::SCHEDULED,
+            ));
+            let predicted_arrival = match i == 0 && stopped {
+                true => stop.predicted_arrival.min(bus.timestamp as i64),
+                false => stop.predicted_arrival,
+            };
+            update.arrival = stop_time_event(stop.scheduled_arrival, predicted_arrival);
+            update.departure = stop_time_event(stop.scheduled_departure, stop.predicted_departure);
+            update
+        })
+        .collect::<Vec<StopTimeUpdate>>();
+
+    let mut trip = trip_descriptor(bus);
+    trip.schedule_relationship = Some(EnumOrUnknown::new(
+        trip_descriptor::ScheduleRelationship::SCHEDULED,
+    ));
+
+    let mut trip_update = TripUpdate::new();
+    trip_update.trip = MessageField::some(trip);
+    trip_update.vehicle = MessageField::some(vehicle_descriptor(bus));
+    trip_update.stop_time_update = stop_time_update;
+    trip_update.timestamp = Some(bus.timestamp);
+    trip_update.delay = Some(delay.round() as i32);
+
+    let mut entity = FeedEntity::new();
+    entity.id = Some(format!("trip-{}", bus.id));
+    entity.trip_update = MessageField::some(trip_update);
+    Some(entity)
+}
+
+fn stop_time_event(scheduled: Option<i64>, predicted: i64) -> MessageField<StopTimeEvent> {
+    let mut event = StopTimeEvent::new();
+    event.time = Some(predicted);
+    if let Some(scheduled) = scheduled {
+        event.delay = Some((predicted - scheduled) as i32);
+    }
+    MessageField::some(event)
+}
@@ -1,59 +1,257 @@
 use crate::{
+    config::FeedConfig,
+    frequency,
     gtfs_realtime::FeedMessage,
+    http::{BreakerState, CircuitBreaker, HttpClient, HttpConfig},
     logger,
-    store::{Bus, Store},
+    recorder::Recorder,
+    source::{self, RealtimeSource},
+    store::{Alert, Bus, Store, TripUpdate},
 };
 
+use chrono::{DateTime, Utc};
 use protobuf::Message;
 use rayon::prelude::*;
-use std::{collections::VecDeque, sync::Arc};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
 
 pub struct Fetcher {
     store: Arc<Store>,
-    api_url: String,
+    sources: Vec<Box<dyn RealtimeSource>>,
+    service: String,
+    breaker: Mutex<CircuitBreaker>,
+    recorder: Option<Recorder>,
 }
 
 impl Fetcher {
-    pub fn new(store: Arc<Store>, api_url: String) -> Self {
-        Self {
-            api_url,
+    pub fn new(store: Arc<Store>, feed: &FeedConfig, http: HttpConfig) -> Result<Self, String> {
+        let service = match store.namespace() {
+            "" => "FETCHER".to_string(),
+            namespace => format!("FETCHER:{}", namespace),
+        };
+
+        let client = Arc::new(HttpClient::new(http)?);
+        let sources = feed
+            .realtime_urls
+            .iter()
+            .map(|url| source::from_url(url, client.clone()))
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let recorder = match &feed.record_dir {
+            Some(dir) => Some(Recorder::new(dir, &service)?),
+            None => None,
+        };
+
+        Ok(Self {
             store: store.clone(),
+            sources,
+            service,
+            breaker: Mutex::new(CircuitBreaker::new(&http)),
+            recorder,
+        })
+    }
+
+    /// Merged feed of every source with its newest header timestamp, `None`
+    /// if none of them changed since the last poll
+    async fn download(&self) -> Result<Option<(FeedMessage, Vec<u8>, u64)>, String> {
+        //Feeds split over several sources (positions, trip updates, alerts)
+        //are merged into a single message
+        let mut message: Option<FeedMessage> = None;
+        let mut buffer = Vec::new();
+        let mut timestamp = 0;
+        let mut modified = false;
+        for source in &self.sources {
+            let snapshot = source
+                .fetch()
+                .await
+                .map_err(|e| format!("{}: {}", source.name(), e))?;
+            modified |= snapshot.modified;
+            let data = snapshot.body;
+
+            let part = FeedMessage::parse_from_bytes(&data)
+                .map_err(|e| format!("{}: Error parsing feed: {}", source.name(), e))?;
+            timestamp = timestamp.max(part.header.timestamp());
+
+            match &mut message {
+                Some(message) => message.entity.extend(part.entity),
+                None => {
+                    message = Some(part);
+                    buffer = data;
+                }
+            }
+        }
+
+        if !modified {
+            return Ok(None);
+        }
+
+        let message = message.ok_or("No realtime source")?;
+        if self.sources.len() > 1 {
+            buffer = message
+                .write_to_bytes()
+                .map_err(|e| format!("Error encoding feed: {}", e))?;
+        }
+
+        Ok(Some((message, buffer, timestamp)))
+    }
+
+    /// Count the poll, returns false if the feed wasn't newer than the last
+    /// processed one
+    async fn count_poll(&self, timestamp: Option<u64>) -> bool {
+        let mut status = self.store.fetch_status().await;
+        let process = match (timestamp, status.last_feed_timestamp) {
+            (None, _) => false,
+            //Feeds without header timestamp are always processed
+            (Some(0), _) | (Some(_), None) => true,
+            (Some(timestamp), Some(last)) => timestamp > last,
+        };
+
+        if process {
+            status.processed_polls += 1;
+            status.last_feed_timestamp = timestamp;
+        } else {
+            status.skipped_polls += 1;
+        }
+
+        self.store.refresh_fetch_status(status).await;
+        process
+    }
+
+    async fn success(&self) {
+        let previous = {
+            let mut breaker = self.breaker.lock().unwrap();
+            let previous = breaker.state();
+            breaker.success();
+            previous
+        };
+
+        if previous != BreakerState::Closed {
+            logger::info(&self.service, "Feed is back, circuit closed");
         }
+
+        let mut status = self.store.fetch_status().await;
+        status.state = BreakerState::Closed;
+        status.consecutive_failures = 0;
+        status.last_success = Some(chrono::Utc::now().timestamp());
+        self.store.refresh_fetch_status(status).await;
+    }
+
+    async fn failure(&self, error: String) {
+        let (previous, state, failures) = {
+            let mut breaker = self.breaker.lock().unwrap();
+            let previous = breaker.state();
+            breaker.failure();
+            (previous, breaker.state(), breaker.failures())
+        };
+
+        logger::critical(&self.service, &error);
+        if previous != BreakerState::Open && state == BreakerState::Open {
+            logger::critical(
+                &self.service,
+                &format!("Circuit open after {} failed polls", failures),
+            );
+        }
+
+        let mut status = self.store.fetch_status().await;
+        status.state = state;
+        status.consecutive_failures = failures;
+        status.last_failure = Some(chrono::Utc::now().timestamp());
+        status.last_error = Some(error);
+        self.store.refresh_fetch_status(status).await;
     }
 
+    /// Poll the sources, again right away while a replay has feeds already
+    /// due
     pub async fn fetch(&self) {
-        logger::fine("FETCHER", "Fetching data");
-        let url = format!("{}", self.api_url).to_string();
+        while self.poll().await && self.sources.iter().any(|source| source.pending()) {}
+    }
+
+    /// Fetch and process the feed once, returns false if the circuit is open
+    async fn poll(&self) -> bool {
+        let (allowed, previous, state) = {
+            let mut breaker = self.breaker.lock().unwrap();
+            let previous = breaker.state();
+            (breaker.allow(), previous, breaker.state())
+        };
+
+        if !allowed {
+            logger::fine(&self.service, "Circuit open, skipping poll");
+            return false;
+        }
 
-        let resp = match ureq::get(&url).call() {
-            Ok(resp) => resp,
+        if previous != state {
+            logger::info(&self.service, "Cooldown over, trying the feed again");
+            let mut status = self.store.fetch_status().await;
+            status.state = state;
+            self.store.refresh_fetch_status(status).await;
+        }
+
+        logger::fine(&self.service, "Fetching data");
+        let downloaded = match self.download().await {
+            Ok(downloaded) => downloaded,
             Err(e) => {
-                logger::critical("FETCHER", &format!("Error fetching data: {}", e));
-                return;
+                self.failure(e).await;
+                return true;
             }
         };
+        self.success().await;
 
-        let mut reader = resp.into_reader();
-        let mut buffer = Vec::new();
-        if std::io::copy(&mut reader, &mut buffer).is_err() {
-            logger::critical("FETCHER", "Error reading response");
-            return;
+        //Every new feed is archived, even the ones skipped below
+        if let (Some(recorder), Some((_, buffer, _))) = (&self.recorder, &downloaded) {
+            if let Err(e) = recorder.record(Utc::now(), buffer.clone()) {
+                logger::warn(&self.service, &e);
+            }
         }
-        let message = FeedMessage::parse_from_bytes(&buffer);
-        let message = match message {
-            Ok(message) => message,
-            Err(_) => return,
+
+        let timestamp = downloaded.as_ref().map(|(_, _, timestamp)| *timestamp);
+        if !self.count_poll(timestamp).await {
+            logger::fine(&self.service, "Feed unchanged, skipping");
+            return true;
+        }
+
+        let (message, buffer, timestamp) = match downloaded {
+            Some(downloaded) => downloaded,
+            None => return true,
+        };
+
+        //Recorded feeds are processed at the time they were fetched
+        let now = match self.sources.iter().any(|source| source.replays()) {
+            true => DateTime::from_timestamp(timestamp as i64, 0)
+                .filter(|_| timestamp > 0)
+                .unwrap_or_else(Utc::now),
+            false => Utc::now(),
         };
 
         let stop_time = std::time::Instant::now();
-        let buses = message
+
+        //Trip updates first, they are used to cross-check the computed delays
+        let trip_updates = message
+            .entity
+            .par_iter()
+            .flat_map(crate::utils::trip_update_data)
+            .collect::<Vec<TripUpdate>>();
+
+        let alerts = message
+            .entity
+            .par_iter()
+            .flat_map(crate::utils::alert_data)
+            .collect::<Vec<Alert>>();
+
+        let (trip_updates_len, alerts_len) = (trip_updates.len(), alerts.len());
+        self.store.refresh_trip_updates(trip_updates).await;
+        self.store.refresh_alerts(alerts).await;
+
+        let mut buses = message
             .entity
             .par_iter()
             .flat_map(|e| {
                 let store = self.store.clone();
-                crate::utils::real_time_data(e, &store)
+                crate::utils::real_time_data(e, &store, now)
             })
             .collect::<VecDeque<Bus>>();
+        frequency::update_headways(&mut buses, &self.store.dataset());
 
         let remove = self
             .store
@@ -74,18 +272,44 @@ impl Fetcher {
             self.store.get_speeds().remove(id);
         });
 
+        let remove = self
+            .store
+            .get_vehicles_state()
+            .par_iter()
+            .flat_map(|res| {
+                let mut vehicle_state = res.value().write().unwrap();
+                vehicle_state.expire -= 1;
+                if vehicle_state.expire == 0 {
+                    Some(res.key().clone())
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<String>>();
+
+        remove.iter().for_each(|id| {
+            self.store.get_vehicles_state().remove(id);
+        });
+
         let stop_time = stop_time.elapsed().as_millis();
         logger::fine(
-            "FETCHER",
+            &self.service,
             &format!(
-                "Refresh time: {}ms, bus length: {:#?}",
+                "Refresh time: {}ms, bus length: {:#?}, trip updates: {}, alerts: {}",
                 stop_time,
-                buses.len()
+                buses.len(),
+                trip_updates_len,
+                alerts_len
             ),
         );
 
         self.store.refresh_raw(buffer).await;
         self.store.refresh(&buses).await;
+        self.store.refresh_predictions(&buses).await;
+        self.store.refresh_observed_trips(&buses).await;
+        self.store.refresh_enriched(&buses).await;
         self.store.refresh_db(&buses).await;
+        self.store.refresh_vehicles(buses).await;
+        true
     }
 }
@@ -0,0 +1,165 @@
+use std::collections::{HashMap, VecDeque};
+
+use gtfs_structures::{ExactTimes, Trip};
+use serde::Serialize;
+
+use crate::{dataset::Dataset, store::Bus, utils};
+
+/// How the delay of a vehicle is evaluated
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EvaluationMode {
+    /// Against the stop times of its trip
+    #[default]
+    Schedule,
+    /// Against the stop times of a frequencies.txt trip with
+    /// `exact_times=1`, shifted to the departure the vehicle runs
+    Frequency,
+    /// Against the headway of a frequencies.txt trip with `exact_times=0`,
+    /// whose departures are not timed
+    Headway,
+}
+
+/// Departure of a frequency-based trip, whose stop times are the ones of
+/// the trip shifted by `offset`
+#[derive(Debug, Clone, Copy)]
+pub struct Instance {
+    /// First departure, in seconds since the start of the service day
+    pub start_time: u32,
+    pub offset: i64,
+    pub headway: u32,
+    pub mode: EvaluationMode,
+}
+
+fn first_departure(trip: &Trip) -> Option<u32> {
+    let first = trip.stop_times.first()?;
+    first.departure_time.or(first.arrival_time)
+}
+
+fn last_arrival(trip: &Trip) -> Option<u32> {
+    let last = trip.stop_times.last()?;
+    last.arrival_time.or(last.departure_time)
+}
+
+/// Shift of the stop times of a trip for the departure at `start_time`
+pub fn offset(trip: &Trip, start_time: u32) -> Option<i64> {
+    Some(start_time as i64 - first_departure(trip)? as i64)
+}
+
+/// First departure and last arrival of a trip during its service day,
+/// over all its departures for a frequency-based trip
+pub fn running_span(trip: &Trip) -> Option<(i64, i64)> {
+    let (first, last) = (first_departure(trip)? as i64, last_arrival(trip)? as i64);
+
+    let start = trip.frequencies.iter().map(|e| e.start_time as i64).min();
+    let end = trip.frequencies.iter().map(|e| e.end_time as i64).max();
+    match (start, end) {
+        (Some(start), Some(end)) => Some((start, end + last - first)),
+        _ => Some((first, last)),
+    }
+}
+
+/// Every departure of a frequency-based trip, one per headway in each of
+/// its frequencies
+pub fn departures(trip: &Trip) -> Vec<u32> {
+    trip.frequencies
+        .iter()
+        .filter(|e| e.headway_secs > 0)
+        .flat_map(|e| (e.start_time..e.end_time).step_by(e.headway_secs as usize))
+        .collect()
+}
+
+/// Departure a vehicle runs: the start time given by the feed, otherwise
+/// the one closest to the vehicle's delay against the trip's own stop times
+///
+/// `None` for a trip not in frequencies.txt
+pub fn instance(trip: &Trip, start_time: Option<u32>, delay: f64) -> Option<Instance> {
+    let first = first_departure(trip)?;
+    let target = match start_time {
+        Some(e) => e as f64,
+        None => first as f64 + delay,
+    };
+
+    let (frequency, closest) = trip
+        .frequencies
+        .iter()
+        .filter(|e| e.headway_secs > 0 && e.end_time > e.start_time)
+        .map(|e| {
+            let last = (e.end_time - 1 - e.start_time) / e.headway_secs;
+            let i = ((target - e.start_time as f64) / e.headway_secs as f64)
+                .round()
+                .clamp(0.0, last as f64) as u32;
+            (e, e.start_time + i * e.headway_secs)
+        })
+        .min_by(|(_, a), (_, b)| {
+            (*a as f64 - target)
+                .abs()
+                .total_cmp(&(*b as f64 - target).abs())
+        })?;
+
+    let start_time = start_time.unwrap_or(closest);
+    Some(Instance {
+        start_time,
+        offset: start_time as i64 - first as i64,
+        headway: frequency.headway_secs,
+        mode: match frequency.exact_times {
+            Some(ExactTimes::ScheduleBased) => EvaluationMode::Frequency,
+            _ => EvaluationMode::Headway,
+        },
+    })
+}
+
+/// Headway of the vehicles evaluated on it: the scheduled running time
+/// from each vehicle to the one ahead of it on the same trip
+pub fn update_headways(buses: &mut VecDeque<Bus>, dataset: &Dataset) {
+    let mut trips: HashMap<(String, Option<String>), Vec<usize>> = HashMap::new();
+    for (i, bus) in buses.iter().enumerate() {
+        if bus.mode == EvaluationMode::Headway {
+            trips
+                .entry((bus.trip_id.clone(), bus.start_date.clone()))
+                .or_default()
+                .push(i);
+        }
+    }
+
+    for ((trip_id, _), mut vehicles) in trips {
+        let (trip, projection) = match (
+            dataset.gtfs.trips.get(&trip_id),
+            dataset.index.get_trip(&trip_id),
+        ) {
+            (Some(trip), Some(projection)) => (trip, projection),
+            _ => continue,
+        };
+
+        vehicles.sort_by(|a, b| {
+            buses[*b]
+                .shape_dist_traveled
+                .total_cmp(&buses[*a].shape_dist_traveled)
+        });
+
+        for pair in vehicles.windows(2) {
+            let (leader, follower) = (&buses[pair[0]], &buses[pair[1]]);
+            let headway = match (
+                utils::get_scheduled_time(
+                    &trip.stop_times,
+                    &projection.stop_distances,
+                    leader.shape_dist_traveled,
+                ),
+                utils::get_scheduled_time(
+                    &trip.stop_times,
+                    &projection.stop_distances,
+                    follower.shape_dist_traveled,
+                ),
+            ) {
+                (Some(leader), Some(follower)) => leader - follower,
+                _ => continue,
+            };
+
+            let follower = &mut buses[pair[1]];
+            follower.set_headway(headway);
+            if let Some(scheduled_headway) = follower.scheduled_headway {
+                follower.set_headway_adherence(headway - scheduled_headway as f64);
+            }
+        }
+    }
+}
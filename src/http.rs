@@ -0,0 +1,424 @@
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use reqwest::{
+    header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    StatusCode,
+};
+use serde::Serialize;
+
+/// Longest wait between two attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy)]
+pub struct HttpConfig {
+    pub connect_timeout: Duration,
+    /// Time allowed between two reads of the response
+    pub read_timeout: Duration,
+    /// Attempts after the first failure
+    pub retries: u32,
+    /// First backoff, doubled on each retry
+    pub backoff: Duration,
+    /// Consecutive failed polls before the breaker opens
+    pub breaker_failures: u32,
+    /// Time the breaker stays open before trying again
+    pub breaker_cooldown: Duration,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(10),
+            retries: 3,
+            backoff: Duration::from_millis(500),
+            breaker_failures: 5,
+            breaker_cooldown: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Headers used to ask the server for the body only if it changed
+#[derive(Debug, Clone, Default)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub body: Vec<u8>,
+    pub validators: Validators,
+}
+
+pub struct HttpClient {
+    client: reqwest::Client,
+    config: HttpConfig,
+}
+
+impl HttpClient {
+    pub fn new(config: HttpConfig) -> Result<Self, String> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(config.connect_timeout)
+            .read_timeout(config.read_timeout)
+            .build()
+            .map_err(|e| format!("Error building http client: {}", e))?;
+
+        Ok(Self { client, config })
+    }
+
+    /// Response of the url, `None` if it didn't change since the response
+    /// the validators come from
+    ///
+    /// Retried with a jittered exponential backoff on network errors, 429
+    /// and 5xx
+    pub async fn get(
+        &self,
+        url: &str,
+        validators: Option<&Validators>,
+    ) -> Result<Option<Response>, String> {
+        let mut attempt = 0;
+        loop {
+            let mut request = self.client.get(url);
+            if let Some(validators) = validators {
+                if let Some(etag) = &validators.etag {
+                    request = request.header(IF_NONE_MATCH, etag);
+                }
+                if let Some(last_modified) = &validators.last_modified {
+                    request = request.header(IF_MODIFIED_SINCE, last_modified);
+                }
+            }
+
+            let error = match request.send().await {
+                Ok(resp) if resp.status() == StatusCode::NOT_MODIFIED => return Ok(None),
+                Ok(resp) if resp.status().is_success() => {
+                    let header = |name| {
+                        resp.headers()
+                            .get(name)
+                            .and_then(|value| value.to_str().ok())
+                            .map(str::to_string)
+                    };
+                    let validators = Validators {
+                        etag: header(ETAG),
+                        last_modified: header(LAST_MODIFIED),
+                    };
+
+                    match resp.bytes().await {
+                        Ok(body) => {
+                            return Ok(Some(Response {
+                                body: body.to_vec(),
+                                validators,
+                            }))
+                        }
+                        Err(e) => format!("Error reading response: {}", e),
+                    }
+                }
+                Ok(resp) => {
+                    let status = resp.status();
+                    if !status.is_server_error() && status != StatusCode::TOO_MANY_REQUESTS {
+                        return Err(format!("Unexpected status {}", status));
+                    }
+                    format!("Unexpected status {}", status)
+                }
+                Err(e) => format!("Error fetching data: {}", e),
+            };
+
+            if attempt >= self.config.retries {
+                return Err(error);
+            }
+
+            tokio::time::sleep(self.backoff(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    /// Full jitter: a random wait up to the exponential backoff
+    fn backoff(&self, attempt: u32) -> Duration {
+        let max = self
+            .config
+            .backoff
+            .saturating_mul(1 << attempt.min(16))
+            .min(MAX_BACKOFF);
+        max.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// Stops polling a failing feed for a while instead of hammering it
+pub struct CircuitBreaker {
+    state: BreakerState,
+    failures: u32,
+    opened_at: Option<Instant>,
+    threshold: u32,
+    cooldown: Duration,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &HttpConfig) -> Self {
+        Self {
+            state: BreakerState::Closed,
+            failures: 0,
+            opened_at: None,
+            threshold: config.breaker_failures.max(1),
+            cooldown: config.breaker_cooldown,
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.state
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Whether a poll may go through, an open breaker lets a single trial
+    /// through once the cooldown is over
+    pub fn allow(&mut self) -> bool {
+        match self.state {
+            BreakerState::Closed | BreakerState::HalfOpen => true,
+            BreakerState::Open => {
+                if self
+                    .opened_at
+                    .is_some_and(|opened_at| opened_at.elapsed() >= self.cooldown)
+                {
+                    self.state = BreakerState::HalfOpen;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    pub fn success(&mut self) {
+        self.state = BreakerState::Closed;
+        self.failures = 0;
+        self.opened_at = None;
+    }
+
+    pub fn failure(&mut self) {
+        self.failures += 1;
+        if self.state == BreakerState::HalfOpen || self.failures >= self.threshold {
+            self.state = BreakerState::Open;
+            self.opened_at = Some(Instant::now());
+        }
+    }
+}
+
+/// Health of the realtime fetch of a feed
+#[derive(Debug, Clone, Serialize)]
+pub struct FetchStatus {
+    pub state: BreakerState,
+    pub consecutive_failures: u32,
+    pub last_success: Option<i64>,
+    pub last_failure: Option<i64>,
+    pub last_error: Option<String>,
+    /// Polls that went through the whole pipeline
+    pub processed_polls: u64,
+    /// Polls skipped because the feed didn't change
+    pub skipped_polls: u64,
+    /// `FeedHeader.timestamp` of the last processed feed
+    pub last_feed_timestamp: Option<u64>,
+}
+
+impl Default for FetchStatus {
+    fn default() -> Self {
+        Self {
+            state: BreakerState::Closed,
+            consecutive_failures: 0,
+            last_success: None,
+            last_failure: None,
+            last_error: None,
+            processed_polls: 0,
+            skipped_polls: 0,
+            last_feed_timestamp: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{extract::State, http::HeaderMap, response::IntoResponse, routing::get, Router};
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    /// Statuses the server answers with in order, then 200
+    #[derive(Default)]
+    struct Server {
+        statuses: Mutex<VecDeque<u16>>,
+        requests: Mutex<Vec<HeaderMap>>,
+    }
+
+    async fn respond(State(server): State<Arc<Server>>, headers: HeaderMap) -> impl IntoResponse {
+        server.requests.lock().unwrap().push(headers);
+        let status = server.statuses.lock().unwrap().pop_front().unwrap_or(200);
+        let status = StatusCode::from_u16(status).unwrap();
+        (status, [(ETAG, HeaderValue::from_static("\"v1\""))], "feed")
+    }
+
+    /// Url of a local server answering with the statuses
+    async fn serve(statuses: &[u16]) -> (String, Arc<Server>) {
+        let server = Arc::new(Server::default());
+        server.statuses.lock().unwrap().extend(statuses);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/feed", listener.local_addr().unwrap());
+        let app = Router::new()
+            .route("/feed", get(respond))
+            .with_state(server.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        (url, server)
+    }
+
+    fn config(retries: u32) -> HttpConfig {
+        HttpConfig {
+            retries,
+            backoff: Duration::from_millis(20),
+            breaker_failures: 2,
+            breaker_cooldown: Duration::from_millis(100),
+            ..HttpConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors() {
+        let (url, server) = serve(&[500, 429, 503]).await;
+        let client = HttpClient::new(config(3)).unwrap();
+
+        let start = Instant::now();
+        let response = client.get(&url, None).await.unwrap().unwrap();
+        assert_eq!(response.body, b"feed");
+        assert_eq!(response.validators.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(server.requests.lock().unwrap().len(), 4);
+
+        //At most 20 + 40 + 80ms of backoff
+        assert!(start.elapsed() < Duration::from_millis(140 + 500));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_retries() {
+        let (url, server) = serve(&[500, 500, 500, 500]).await;
+        let client = HttpClient::new(config(2)).unwrap();
+
+        let error = client.get(&url, None).await.unwrap_err();
+        assert_eq!(error, "Unexpected status 500 Internal Server Error");
+        assert_eq!(server.requests.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let (url, server) = serve(&[404]).await;
+        let client = HttpClient::new(config(3)).unwrap();
+
+        assert!(client.get(&url, None).await.is_err());
+        assert_eq!(server.requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn not_modified_is_none() {
+        let (url, server) = serve(&[304]).await;
+        let client = HttpClient::new(config(3)).unwrap();
+        let validators = Validators {
+            etag: Some("\"v1\"".to_string()),
+            last_modified: Some("Sun, 18 Oct 2026 10:00:00 GMT".to_string()),
+        };
+
+        assert!(client.get(&url, Some(&validators)).await.unwrap().is_none());
+        let requests = server.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0][IF_NONE_MATCH], "\"v1\"");
+        assert_eq!(
+            requests[0][IF_MODIFIED_SINCE],
+            "Sun, 18 Oct 2026 10:00:00 GMT"
+        );
+    }
+
+    #[test]
+    fn backoff_is_bounded() {
+        let client = HttpClient::new(HttpConfig {
+            backoff: Duration::from_millis(500),
+            ..HttpConfig::default()
+        })
+        .unwrap();
+
+        for attempt in 0..40 {
+            let max = Duration::from_millis(500 << attempt.min(16)).min(MAX_BACKOFF);
+            let backoffs = (0..100)
+                .map(|_| client.backoff(attempt))
+                .collect::<Vec<_>>();
+            assert!(backoffs.iter().all(|backoff| *backoff <= max));
+            //Jittered, not always the same wait
+            assert!(backoffs.iter().any(|backoff| *backoff != backoffs[0]));
+        }
+    }
+
+    #[tokio::test]
+    async fn breaker_opens_and_closes() {
+        let (url, server) = serve(&[500, 500, 500, 500, 500, 500]).await;
+        let client = HttpClient::new(config(0)).unwrap();
+        let mut breaker = CircuitBreaker::new(&client.config);
+
+        //Poll the way the fetcher does
+        let poll = |breaker: &mut CircuitBreaker| {
+            let allowed = breaker.allow();
+            let url = url.clone();
+            let client = &client;
+            async move {
+                match allowed {
+                    true => Some(client.get(&url, None).await.is_ok()),
+                    false => None,
+                }
+            }
+        };
+
+        for failures in 1..=2 {
+            assert_eq!(breaker.state(), BreakerState::Closed);
+            assert_eq!(poll(&mut breaker).await, Some(false));
+            breaker.failure();
+            assert_eq!(breaker.failures(), failures);
+        }
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        //No request while open
+        assert_eq!(poll(&mut breaker).await, None);
+        assert_eq!(server.requests.lock().unwrap().len(), 2);
+
+        //A failed trial opens it again
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(poll(&mut breaker).await, Some(false));
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        breaker.failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(!breaker.allow());
+
+        server.statuses.lock().unwrap().clear();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(poll(&mut breaker).await, Some(true));
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        breaker.success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert_eq!(breaker.failures(), 0);
+        assert_eq!(server.requests.lock().unwrap().len(), 4);
+    }
+}
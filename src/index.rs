@@ -0,0 +1,316 @@
+use std::{collections::HashMap, sync::Arc};
+
+use gtfs_structures::{Gtfs, Shape, Stop, StopTime};
+use rayon::prelude::*;
+
+use crate::{
+    logger, matching,
+    quadtree::{Coordinate, Extent, QuadTree},
+    utils::earth_distance,
+};
+
+/// Projection of a trip's stops on its shape
+pub struct TripProjection {
+    pub shape_id: String,
+    /// Distance along the shape of each stop (m)
+    pub stop_distances: Vec<f64>,
+}
+
+/// Indexes derived from a GTFS load, built once per refresh
+pub struct GtfsIndex {
+    trips: HashMap<String, Arc<TripProjection>>,
+    shapes: HashMap<String, Arc<Vec<f64>>>,
+    stop_times: HashMap<String, Vec<(Arc<str>, usize)>>,
+    stops: QuadTree<String>,
+}
+
+impl Default for GtfsIndex {
+    fn default() -> Self {
+        Self {
+            trips: HashMap::new(),
+            shapes: HashMap::new(),
+            stop_times: HashMap::new(),
+            stops: QuadTree::new(Extent::world()),
+        }
+    }
+}
+
+impl GtfsIndex {
+    pub fn build(gtfs: &Gtfs) -> Self {
+        let start = std::time::Instant::now();
+
+        let shapes = gtfs
+            .shapes
+            .par_iter()
+            .map(|(id, shape)| (id.clone(), Arc::new(cumulative_distances(shape))))
+            .collect::<HashMap<String, Arc<Vec<f64>>>>();
+
+        //Trips sharing the same shape and stops share the same projection
+        let mut patterns: HashMap<(&str, Vec<&str>), Vec<&str>> = HashMap::new();
+        for trip in gtfs.trips.values() {
+            let shape_id = match &trip.shape_id {
+                Some(e) => e,
+                None => continue,
+            };
+
+            if trip.stop_times.is_empty() {
+                continue;
+            }
+
+            let stops = trip
+                .stop_times
+                .iter()
+                .map(|stop| stop.stop.id.as_str())
+                .collect::<Vec<&str>>();
+            patterns
+                .entry((shape_id.as_str(), stops))
+                .or_default()
+                .push(trip.id.as_str());
+        }
+
+        let patterns_len = patterns.len();
+        let trips = patterns
+            .into_par_iter()
+            .flat_map(|((shape_id, _), trip_ids)| {
+                let shape = gtfs.shapes.get(shape_id)?;
+                let distances = shapes.get(shape_id)?;
+                let stops = &gtfs.trips.get(trip_ids[0])?.stop_times;
+                let projection = Arc::new(project_trip(shape_id, stops, shape, distances)?);
+
+                Some(
+                    trip_ids
+                        .into_iter()
+                        .map(|trip_id| (trip_id.to_string(), projection.clone()))
+                        .collect::<Vec<(String, Arc<TripProjection>)>>(),
+                )
+            })
+            .flatten()
+            .collect::<HashMap<String, Arc<TripProjection>>>();
+
+        let mut stop_times: HashMap<String, Vec<(Arc<str>, usize)>> = HashMap::new();
+        for trip in gtfs.trips.values() {
+            let trip_id: Arc<str> = Arc::from(trip.id.as_str());
+            for (i, stop_time) in trip.stop_times.iter().enumerate() {
+                stop_times
+                    .entry(stop_time.stop.id.clone())
+                    .or_default()
+                    .push((trip_id.clone(), i));
+            }
+        }
+
+        let mut stops = QuadTree::new(Extent::world());
+        let world = Extent::world();
+        for stop in gtfs.stops.values() {
+            if let (Some(latitude), Some(longitude)) = (stop.latitude, stop.longitude) {
+                let coord = Coordinate::new(longitude, latitude);
+                if world.contains(&coord) {
+                    stops.insert(&coord, stop.id.clone());
+                }
+            }
+        }
+
+        logger::fine(
+            "INDEX",
+            &format!(
+                "Built index in {}ms: {} shapes, {} patterns, {} trips",
+                start.elapsed().as_millis(),
+                shapes.len(),
+                patterns_len,
+                trips.len()
+            ),
+        );
+
+        Self {
+            trips,
+            shapes,
+            stop_times,
+            stops,
+        }
+    }
+
+    pub fn get_trip(&self, trip_id: &str) -> Option<Arc<TripProjection>> {
+        self.trips.get(trip_id).cloned()
+    }
+
+    /// Trips calling at the stop, with the index of the stop in the trip
+    pub fn get_stop_times(&self, stop_id: &str) -> &[(Arc<str>, usize)] {
+        match self.stop_times.get(stop_id) {
+            Some(e) => e,
+            None => &[],
+        }
+    }
+
+    /// Stops inside the extent (longitude/latitude)
+    pub fn find_stops(&self, gtfs: &Gtfs, extent: &Extent) -> Vec<Arc<Stop>> {
+        self.stops
+            .find_bbox(extent)
+            .into_iter()
+            .flat_map(|id| gtfs.stops.get(id))
+            .cloned()
+            .collect()
+    }
+
+    /// Closest stops to a position with their distance (m)
+    pub fn nearest_stops(
+        &self,
+        gtfs: &Gtfs,
+        latitude: f64,
+        longitude: f64,
+        limit: usize,
+    ) -> Vec<(Arc<Stop>, f64)> {
+        self.stops
+            .nearest_k(&Coordinate::new(longitude, latitude), limit)
+            .into_iter()
+            .flat_map(|(id, distance)| Some((gtfs.stops.get(id)?.clone(), distance)))
+            .collect()
+    }
+
+    /// Cumulative distance along the shape of each shape point (m)
+    pub fn get_shape_distances(&self, shape_id: &str) -> Option<Arc<Vec<f64>>> {
+        self.shapes.get(shape_id).cloned()
+    }
+}
+
+fn cumulative_distances(shape: &[Shape]) -> Vec<f64> {
+    let mut distances = Vec::with_capacity(shape.len());
+    let mut total = 0.0;
+    for (i, point) in shape.iter().enumerate() {
+        if i > 0 {
+            total += earth_distance(
+                (shape[i - 1].latitude, shape[i - 1].longitude),
+                (point.latitude, point.longitude),
+            );
+        }
+        distances.push(total);
+    }
+    distances
+}
+
+fn project_trip(
+    shape_id: &str,
+    stops: &[StopTime],
+    shape: &[Shape],
+    distances: &[f64],
+) -> Option<TripProjection> {
+    let stops = stops
+        .iter()
+        .map(|stop| Some((stop.stop.latitude?, stop.stop.longitude?)))
+        .collect::<Option<Vec<(f64, f64)>>>();
+
+    let stops = match stops {
+        Some(e) => e,
+        None => {
+            logger::warn("INDEX", &format!("Stop without position on {}", shape_id));
+            return None;
+        }
+    };
+
+    let stop_distances = match matching::match_stops(shape, distances, &stops) {
+        Some(e) => e,
+        None => {
+            logger::warn("INDEX", &format!("Stops not matched on {}", shape_id));
+            return None;
+        }
+    };
+
+    Some(TripProjection {
+        shape_id: shape_id.to_string(),
+        stop_distances,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use gtfs_structures::Trip;
+
+    use super::*;
+
+    /// Generated network of `lines` lines, each with a wavy shape of 400
+    /// points (about 20km) served by 40 stops, run by `trips` trips
+    fn network(lines: usize, trips: usize) -> Gtfs {
+        let mut gtfs = Gtfs::default();
+        for line in 0..lines {
+            let (lat, lon) = (
+                50.0 + (line / 20) as f64 * 0.05,
+                4.0 + (line % 20) as f64 * 0.05,
+            );
+            let point = |i: usize| {
+                let t = i as f64 / 400.0;
+                (lat + 0.18 * t, lon + 0.01 * (t * 40.0).sin())
+            };
+
+            let shape_id = format!("shape{}", line);
+            let shape = (0..400)
+                .map(|i| Shape {
+                    id: shape_id.clone(),
+                    latitude: point(i).0,
+                    longitude: point(i).1,
+                    sequence: i,
+                    dist_traveled: None,
+                })
+                .collect::<Vec<_>>();
+            gtfs.shapes.insert(shape_id.clone(), shape);
+
+            let stops = (0..40)
+                .map(|i| {
+                    let (latitude, longitude) = point(i * 10);
+                    let stop = Arc::new(Stop {
+                        id: format!("stop{}-{}", line, i),
+                        latitude: Some(latitude + 0.0001),
+                        longitude: Some(longitude),
+                        ..Default::default()
+                    });
+                    gtfs.stops.insert(stop.id.clone(), stop.clone());
+                    stop
+                })
+                .collect::<Vec<_>>();
+
+            for trip in 0..trips {
+                let id = format!("trip{}-{}", line, trip);
+                let start = 18000 + trip as u32 * 600;
+                let stop_times = stops
+                    .iter()
+                    .enumerate()
+                    .map(|(i, stop)| StopTime {
+                        stop: stop.clone(),
+                        stop_sequence: i as u16,
+                        arrival_time: Some(start + i as u32 * 60),
+                        departure_time: Some(start + i as u32 * 60),
+                        ..Default::default()
+                    })
+                    .collect();
+                let trip = Trip {
+                    id: id.clone(),
+                    shape_id: Some(shape_id.clone()),
+                    stop_times,
+                    ..Default::default()
+                };
+                gtfs.trips.insert(id, trip);
+            }
+        }
+        gtfs
+    }
+
+    #[test]
+    fn trips_share_projection_of_their_pattern() {
+        let gtfs = network(3, 4);
+        let index = GtfsIndex::build(&gtfs);
+
+        let first = index.get_trip("trip0-0").unwrap();
+        assert!(Arc::ptr_eq(&first, &index.get_trip("trip0-3").unwrap()));
+        assert!(!Arc::ptr_eq(&first, &index.get_trip("trip1-0").unwrap()));
+        assert_eq!(first.shape_id, "shape0");
+
+        let shape = index.get_shape_distances("shape0").unwrap();
+        assert_eq!(first.stop_distances.len(), 40);
+        assert!(first.stop_distances.windows(2).all(|e| e[0] < e[1]));
+        for (i, distance) in first.stop_distances.iter().enumerate() {
+            assert!((distance - shape[i * 10]).abs() < 15.0);
+        }
+
+        assert_eq!(index.get_stop_times("stop1-5").len(), 4);
+        assert!(index.get_stop_times("stop1-5").iter().all(|(_, i)| *i == 5));
+        let nearest = index.nearest_stops(&gtfs, 50.0, 4.0, 1);
+        assert_eq!(nearest[0].0.id, "stop0-0");
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use chrono::{Days, NaiveDate};
+use gtfs_structures::Trip;
+
+use crate::{calendar, dataset::Dataset, frequency, matching, timezone, utils};
+
+/// Fixes of a vehicle kept to infer its trip
+pub const MAX_POSITIONS: usize = 5;
+/// Stops around the vehicle whose trips are candidates
+const CANDIDATE_STOPS: usize = 10;
+/// How far from the vehicle these stops may be (m)
+const CANDIDATE_RADIUS: f64 = 2000.0;
+/// Slack around the running span of a trip for it to be a candidate (s)
+const RUNNING_SLACK: i64 = 1800;
+/// A fix further from the shape rules the trip out (m)
+const MAX_SHAPE_DISTANCE: f64 = 150.0;
+/// A vehicle moving back along the shape more than this runs the other
+/// way (m)
+const MAX_BACKWARD: f64 = 50.0;
+/// Distance to the shape (m) and schedule deviation (s) dividing the score
+/// by e
+const DISTANCE_SCALE: f64 = 50.0;
+const DEVIATION_SCALE: f64 = 600.0;
+/// How much better another trip has to score to replace the previous one
+const SWITCH_MARGIN: f64 = 1.25;
+/// Below this confidence no trip is assigned
+const MIN_CONFIDENCE: f64 = 0.2;
+
+/// Fix of a vehicle: timestamp, latitude and longitude
+pub type Position = (u64, f32, f32);
+
+pub struct Inference<'a> {
+    pub trip: &'a Trip,
+    pub service_date: NaiveDate,
+    /// From 0 to 1, how well the trip fits and how clearly it beats the
+    /// other candidates
+    pub confidence: f64,
+}
+
+struct Candidate<'a> {
+    trip: &'a Trip,
+    service_date: NaiveDate,
+    score: f64,
+}
+
+/// Most likely trip of a vehicle the feed gives no (known) trip for, from
+/// its recent positions (oldest first), its route if known and the trips
+/// running around `now`
+///
+/// Each candidate scores on the distance of the positions to its shape and
+/// on the deviation from its schedule at the last one. The previous trip of
+/// the vehicle is kept unless another one clearly scores better.
+pub fn infer_trip<'a>(
+    dataset: &'a Dataset,
+    route_id: Option<&str>,
+    positions: &VecDeque<Position>,
+    previous: &str,
+    now: i64,
+) -> Option<Inference<'a>> {
+    let (_, latitude, longitude) = *positions.back()?;
+    let gtfs = &dataset.gtfs;
+
+    let mut trip_ids = dataset
+        .index
+        .nearest_stops(gtfs, latitude as f64, longitude as f64, CANDIDATE_STOPS)
+        .into_iter()
+        .filter(|(_, distance)| *distance <= CANDIDATE_RADIUS)
+        .flat_map(|(stop, _)| dataset.index.get_stop_times(&stop.id))
+        .map(|(trip_id, _)| trip_id.as_ref())
+        .collect::<HashSet<&str>>();
+    if !previous.is_empty() {
+        trip_ids.insert(previous);
+    }
+
+    let today = timezone::date(&dataset.timezone, now);
+    let mut shapes: HashMap<&str, Option<(f64, f64)>> = HashMap::new();
+    let mut candidates = Vec::new();
+    for trip_id in trip_ids {
+        let trip = match gtfs.trips.get(trip_id) {
+            Some(e) => e,
+            None => continue,
+        };
+        if route_id.is_some_and(|route_id| trip.route_id != route_id) {
+            continue;
+        }
+
+        let (first_departure, last_arrival) = match frequency::running_span(trip) {
+            Some(e) => e,
+            None => continue,
+        };
+        let (shape_id, projection) = match (&trip.shape_id, dataset.index.get_trip(trip_id)) {
+            (Some(shape_id), Some(projection)) => (shape_id, projection),
+            _ => continue,
+        };
+
+        //Trips of a shape share its matching
+        let matched = *shapes
+            .entry(shape_id.as_str())
+            .or_insert_with(|| match_positions(dataset, shape_id, positions));
+        let (distance, shape_dist_traveled) = match matched {
+            Some(e) => e,
+            None => continue,
+        };
+
+        let scheduled = match utils::get_scheduled_time(
+            &trip.stop_times,
+            &projection.stop_distances,
+            shape_dist_traveled,
+        ) {
+            Some(e) => e,
+            None => continue,
+        };
+
+        for service_date in [today, today - Days::new(1)] {
+            let service_day_start = timezone::service_day_start(&dataset.timezone, service_date);
+            let time = now - service_day_start;
+            if time < first_departure - RUNNING_SLACK
+                || time > last_arrival + RUNNING_SLACK
+                || !calendar::is_active(gtfs, &trip.service_id, service_date)
+            {
+                continue;
+            }
+
+            let mut deviation = time as f64 - scheduled;
+            if let Some(instance) = frequency::instance(trip, None, deviation) {
+                deviation -= instance.offset as f64;
+            }
+
+            candidates.push(Candidate {
+                trip,
+                service_date,
+                score: (-distance / DISTANCE_SCALE).exp()
+                    * (-deviation.abs() / DEVIATION_SCALE).exp(),
+            });
+        }
+    }
+
+    let total = candidates.iter().map(|e| e.score).sum::<f64>();
+    let best = candidates
+        .iter()
+        .max_by(|a, b| a.score.total_cmp(&b.score))?;
+    let kept = candidates
+        .iter()
+        .filter(|e| e.trip.id == previous)
+        .max_by(|a, b| a.score.total_cmp(&b.score))
+        .filter(|e| best.score < e.score * SWITCH_MARGIN)
+        .unwrap_or(best);
+
+    let confidence = match total > 0.0 {
+        true => kept.score * kept.score / total,
+        false => 0.0,
+    };
+    if confidence < MIN_CONFIDENCE {
+        return None;
+    }
+
+    Some(Inference {
+        trip: kept.trip,
+        service_date: kept.service_date,
+        confidence,
+    })
+}
+
+/// Mean distance of the positions to the shape and progress along it at
+/// the last one, `None` if a position is too far or the vehicle goes the
+/// other way
+fn match_positions(
+    dataset: &Dataset,
+    shape_id: &str,
+    positions: &VecDeque<Position>,
+) -> Option<(f64, f64)> {
+    let shape = dataset.gtfs.shapes.get(shape_id)?;
+    let distances = dataset.index.get_shape_distances(shape_id)?;
+
+    let mut previous: Option<f64> = None;
+    let mut previous_at = 0;
+    let mut total = 0.0;
+    for (timestamp, latitude, longitude) in positions {
+        let matched = matching::match_vehicle(
+            shape,
+            &distances,
+            *latitude as f64,
+            *longitude as f64,
+            previous.map(|e| e - MAX_BACKWARD),
+            timestamp.saturating_sub(previous_at),
+            None,
+        )?;
+        if matched.distance > MAX_SHAPE_DISTANCE {
+            return None;
+        }
+        //The match itself is floored at the previous one
+        if previous.is_some_and(|e| matched.projected < e - MAX_BACKWARD) {
+            return None;
+        }
+
+        total += matched.distance;
+        previous = Some(matched.shape_dist_traveled);
+        previous_at = *timestamp;
+    }
+
+    Some((total / positions.len() as f64, previous?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use gtfs_structures::Shape;
+
+    use super::*;
+    use crate::index::GtfsIndex;
+
+    /// Dataset with a single shape going 3km east
+    fn dataset() -> Dataset {
+        let mut dataset = Dataset::default();
+        let shape = [(50.0, 4.0), (50.0, 4.042)]
+            .iter()
+            .enumerate()
+            .map(|(i, &(latitude, longitude))| Shape {
+                id: "shape".to_string(),
+                latitude,
+                longitude,
+                sequence: i,
+                dist_traveled: None,
+            })
+            .collect();
+        dataset.gtfs.shapes = HashMap::from([("shape".to_string(), shape)]);
+        dataset.index = GtfsIndex::build(&dataset.gtfs);
+        dataset
+    }
+
+    #[test]
+    fn positions_along_shape_are_matched() {
+        let dataset = dataset();
+        let positions = VecDeque::from([
+            (0, 50.0001, 4.014),
+            (15, 50.0001, 4.016),
+            (30, 50.0001, 4.018),
+            (45, 50.0001, 4.02),
+        ]);
+
+        let (distance, progress) = match_positions(&dataset, "shape", &positions).unwrap();
+        assert!(distance < 15.0);
+        assert!((progress - 1430.0).abs() < 15.0);
+    }
+
+    #[test]
+    fn positions_going_back_are_rejected() {
+        let dataset = dataset();
+        //100m back at each fix, all near the shape
+        let positions = VecDeque::from([
+            (0, 50.0001, 4.02),
+            (15, 50.0001, 4.0186),
+            (30, 50.0001, 4.0172),
+        ]);
+
+        assert!(match_positions(&dataset, "shape", &positions).is_none());
+    }
+}
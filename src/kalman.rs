@@ -0,0 +1,335 @@
+/// Standard deviation of a matched position along the shape (m)
+const POSITION_NOISE: f64 = 10.0;
+/// Standard deviation of a speed reported by the vehicle (m/s)
+const SPEED_NOISE: f64 = 1.0;
+/// Standard deviation of the acceleration of a vehicle (m/s²)
+const ACCELERATION_NOISE: f64 = 0.5;
+/// How long an acceleration lasts before it fades out (s)
+const MANEUVER_TIME: f64 = 10.0;
+/// Initial standard deviation of the speed when the vehicle reports none
+/// (m/s)
+const INITIAL_SPEED_NOISE: f64 = 10.0;
+/// Integration steps of the process noise, an even number
+const NOISE_STEPS: usize = 16;
+/// Squared normalized innovation above which a position is a GPS jump
+/// (4 standard deviations)
+const GATE: f64 = 16.0;
+/// Consecutive rejected positions after which the vehicle is trusted to
+/// really be there and the filter restarts on it
+const MAX_REJECTED: usize = 3;
+
+/// Estimate of a vehicle's state at the time of its last position
+#[derive(Debug, Clone, Copy)]
+pub struct Estimate {
+    /// Distance along the shape (m)
+    pub shape_dist_traveled: f64,
+    /// Speed along the shape (m/s)
+    pub speed: f64,
+    /// Acceleration along the shape (m/s²)
+    pub acceleration: f64,
+    /// Whether the last position was rejected as a GPS jump
+    pub rejected: bool,
+}
+
+/// Kalman filter of a vehicle's progress along the shape of its trip
+///
+/// The acceleration follows the Singer model: random, and fading out after
+/// `MANEUVER_TIME`, since vehicles don't accelerate for long. Extrapolated
+/// over a gap between positions, it stays within a plausible range, which
+/// a constant acceleration would not.
+///
+/// Positions come from the shape matching, speeds from the vehicle when it
+/// reports them
+#[derive(Debug, Clone)]
+pub struct KalmanFilter {
+    timestamp: u64,
+    /// Distance along the shape, speed and acceleration
+    state: [f64; 3],
+    covariance: [[f64; 3]; 3],
+    rejected: usize,
+}
+
+impl KalmanFilter {
+    pub fn new(timestamp: u64, shape_dist_traveled: f64, speed: Option<f64>) -> Self {
+        let speed_noise = match speed {
+            Some(_) => SPEED_NOISE,
+            None => INITIAL_SPEED_NOISE,
+        };
+
+        Self {
+            timestamp,
+            state: [shape_dist_traveled, speed.unwrap_or(0.0).max(0.0), 0.0],
+            covariance: [
+                [POSITION_NOISE.powi(2), 0.0, 0.0],
+                [0.0, speed_noise.powi(2), 0.0],
+                [0.0, 0.0, ACCELERATION_NOISE.powi(2)],
+            ],
+            rejected: 0,
+        }
+    }
+
+    pub fn estimate(&self) -> Estimate {
+        Estimate {
+            shape_dist_traveled: self.state[0],
+            speed: self.state[1],
+            acceleration: self.state[2],
+            rejected: self.rejected > 0,
+        }
+    }
+
+    /// Filter a new position (and speed), positions older than the last
+    /// one are ignored
+    pub fn update(
+        &mut self,
+        timestamp: u64,
+        shape_dist_traveled: f64,
+        speed: Option<f64>,
+    ) -> Estimate {
+        if timestamp <= self.timestamp {
+            return self.estimate();
+        }
+
+        let previous = self.state[0];
+        self.predict((timestamp - self.timestamp) as f64);
+        self.timestamp = timestamp;
+
+        let innovation = shape_dist_traveled - self.state[0];
+        let variance = self.covariance[0][0] + POSITION_NOISE.powi(2);
+        if innovation.powi(2) / variance > GATE {
+            self.rejected += 1;
+            match self.rejected >= MAX_REJECTED {
+                true => *self = Self::new(timestamp, shape_dist_traveled, speed),
+                false => self.state[0] = self.state[0].max(previous),
+            }
+            return self.estimate();
+        }
+        self.rejected = 0;
+
+        self.correct(0, shape_dist_traveled, POSITION_NOISE);
+        if let Some(speed) = speed {
+            self.correct(1, speed, SPEED_NOISE);
+        }
+
+        //Vehicles don't go back along their trip
+        self.state[0] = self.state[0].max(previous);
+        self.state[1] = self.state[1].max(0.0);
+        self.estimate()
+    }
+
+    fn predict(&mut self, dt: f64) {
+        let f = transition(dt);
+
+        let state = self.state;
+        for (i, row) in f.iter().enumerate() {
+            self.state[i] = (0..3).map(|j| row[j] * state[j]).sum();
+        }
+
+        //F P F^T + Q
+        let p = self.covariance;
+        let q = process_noise(dt);
+        for i in 0..3 {
+            for j in 0..3 {
+                let fpf = (0..3)
+                    .flat_map(|k| (0..3).map(move |l| (k, l)))
+                    .map(|(k, l)| f[i][k] * p[k][l] * f[j][l])
+                    .sum::<f64>();
+                self.covariance[i][j] = fpf + q[i][j];
+            }
+        }
+    }
+
+    /// Update with a direct measurement of one component of the state
+    fn correct(&mut self, component: usize, measurement: f64, noise: f64) {
+        let p = self.covariance;
+        let variance = p[component][component] + noise.powi(2);
+        let innovation = measurement - self.state[component];
+
+        let gain = [0, 1, 2].map(|i| p[i][component] / variance);
+        for (i, gain) in gain.iter().enumerate() {
+            self.state[i] += gain * innovation;
+            self.covariance[i] = [0, 1, 2].map(|j| p[i][j] - gain * p[component][j]);
+        }
+    }
+}
+
+/// State transition over `dt`, the acceleration fading out
+fn transition(dt: f64) -> [[f64; 3]; 3] {
+    let alpha = 1.0 / MANEUVER_TIME;
+    let decay = (-alpha * dt).exp();
+    [
+        [1.0, dt, (alpha * dt - 1.0 + decay) / alpha.powi(2)],
+        [0.0, 1.0, (1.0 - decay) / alpha],
+        [0.0, 0.0, decay],
+    ]
+}
+
+/// Noise added to the state over `dt` by the random acceleration,
+/// integrated with Simpson's rule
+fn process_noise(dt: f64) -> [[f64; 3]; 3] {
+    let alpha = 1.0 / MANEUVER_TIME;
+    let step = dt / NOISE_STEPS as f64;
+
+    let mut q = [[0.0; 3]; 3];
+    for k in 0..=NOISE_STEPS {
+        let weight = match k {
+            0 => 1.0,
+            k if k == NOISE_STEPS => 1.0,
+            k if k % 2 == 1 => 4.0,
+            _ => 2.0,
+        } * step
+            / 3.0;
+
+        //How an acceleration k steps back weighs on the state now
+        let f = transition(k as f64 * step);
+        let column = [f[0][2], f[1][2], f[2][2]];
+        for i in 0..3 {
+            for j in 0..3 {
+                q[i][j] += weight * column[i] * column[j];
+            }
+        }
+    }
+
+    let scale = 2.0 * alpha * ACCELERATION_NOISE.powi(2);
+    q.map(|row| row.map(|e| e * scale))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Uniform noise in [-1, 1], the same on every run
+    fn noise(seed: &mut u64) -> f64 {
+        *seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (*seed >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0
+    }
+
+    /// Vehicle at 10 m/s with a fix every 15s, 20m of noise on positions
+    fn track(filter: &mut KalmanFilter, seed: &mut u64, from: u64, to: u64) -> Estimate {
+        let mut estimate = filter.estimate();
+        for i in from..to {
+            let position = 150.0 * i as f64 + 20.0 * noise(seed);
+            estimate = filter.update(15 * i, position, None);
+        }
+        estimate
+    }
+
+    #[test]
+    fn speed_converges_without_reported_speed() {
+        let mut seed = 1;
+        let mut filter = KalmanFilter::new(0, 0.0, None);
+
+        let estimate = track(&mut filter, &mut seed, 1, 60);
+        assert!((estimate.speed - 10.0).abs() < 1.0, "{:?}", estimate);
+        assert!((estimate.shape_dist_traveled - 150.0 * 59.0).abs() < 20.0);
+        assert!(estimate.acceleration.abs() < 0.1);
+        assert!(!estimate.rejected);
+    }
+
+    #[test]
+    fn reported_speed_is_followed() {
+        let mut filter = KalmanFilter::new(0, 0.0, Some(10.0));
+        for i in 1..10 {
+            filter.update(15 * i, 150.0 * i as f64, Some(10.0));
+        }
+
+        let estimate = filter.update(150, 1500.0, Some(10.0));
+        assert!((estimate.speed - 10.0).abs() < 0.1);
+        assert!((estimate.shape_dist_traveled - 1500.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn single_jump_is_rejected() {
+        let mut seed = 2;
+        let mut filter = KalmanFilter::new(0, 0.0, None);
+        track(&mut filter, &mut seed, 1, 40);
+
+        let estimate = filter.update(15 * 40, 150.0 * 40.0 + 1000.0, None);
+        assert!(estimate.rejected);
+        assert!((estimate.shape_dist_traveled - 150.0 * 40.0).abs() < 30.0);
+
+        //Back on track
+        let estimate = track(&mut filter, &mut seed, 41, 42);
+        assert!(!estimate.rejected);
+        assert!((estimate.shape_dist_traveled - 150.0 * 41.0).abs() < 30.0);
+    }
+
+    #[test]
+    fn restarts_after_consecutive_outliers() {
+        let mut seed = 3;
+        let mut filter = KalmanFilter::new(0, 0.0, None);
+        track(&mut filter, &mut seed, 1, 40);
+
+        //Always too far to be where the vehicle was heading
+        let last = 40 + MAX_REJECTED as u64 - 1;
+        for i in 40..last {
+            let estimate = filter.update(15 * i, 150.0 * i as f64 + 5000.0, None);
+            assert!(estimate.rejected);
+            assert!((estimate.shape_dist_traveled - 150.0 * i as f64).abs() < 100.0);
+        }
+
+        let estimate = filter.update(15 * last, 150.0 * last as f64 + 5000.0, Some(8.0));
+        assert!(!estimate.rejected);
+        assert_eq!(estimate.shape_dist_traveled, 150.0 * last as f64 + 5000.0);
+        assert_eq!(estimate.speed, 8.0);
+        assert_eq!(estimate.acceleration, 0.0);
+    }
+
+    #[test]
+    fn follows_relocated_vehicle() {
+        let mut seed = 4;
+        let mut filter = KalmanFilter::new(0, 0.0, None);
+        track(&mut filter, &mut seed, 1, 40);
+
+        //Rejected at first, then the filter catches up on its own or
+        //restarts
+        let estimate = filter.update(15 * 40, 150.0 * 40.0 + 1000.0, None);
+        assert!(estimate.rejected);
+
+        let mut estimate = estimate;
+        for i in 41..40 + MAX_REJECTED as u64 {
+            estimate = filter.update(15 * i, 150.0 * i as f64 + 1000.0, None);
+        }
+        let last = 40 + MAX_REJECTED as u64 - 1;
+        assert!(!estimate.rejected);
+        assert!((estimate.shape_dist_traveled - (150.0 * last as f64 + 1000.0)).abs() < 50.0);
+    }
+
+    #[test]
+    fn older_positions_are_ignored() {
+        let mut filter = KalmanFilter::new(100, 1000.0, Some(10.0));
+
+        let estimate = filter.update(100, 2000.0, None);
+        assert_eq!(estimate.shape_dist_traveled, 1000.0);
+        let estimate = filter.update(50, 0.0, None);
+        assert_eq!(estimate.shape_dist_traveled, 1000.0);
+    }
+
+    #[test]
+    fn progress_never_goes_back() {
+        let mut seed = 5;
+        let mut filter = KalmanFilter::new(0, 0.0, None);
+        let mut previous = track(&mut filter, &mut seed, 1, 20).shape_dist_traveled;
+
+        //Braking hard, then positions going back, some far enough to be
+        //rejected
+        let positions = [
+            2900.0, 2950.0, 2960.0, 2940.0, 2900.0, 2800.0, 2850.0, 2500.0,
+        ];
+        for (i, position) in positions.into_iter().enumerate() {
+            let estimate = filter.update(15 * (20 + i as u64), position, Some(0.0));
+            assert!(estimate.shape_dist_traveled >= previous, "{:?}", estimate);
+            previous = estimate.shape_dist_traveled;
+        }
+    }
+
+    #[test]
+    fn speed_never_negative() {
+        let mut filter = KalmanFilter::new(0, 1000.0, Some(0.0));
+        for i in 1..10 {
+            let estimate = filter.update(15 * i, 1000.0 - 2.0 * i as f64, None);
+            assert!(estimate.speed >= 0.0);
+        }
+    }
+}
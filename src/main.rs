@@ -3,21 +3,39 @@ use database::Db;
 use dotenv::dotenv;
 use std::env;
 use std::sync::Arc;
-use tokio::time::{interval, Duration};
+use tokio::time::{interval, Duration, Instant};
 
 mod api;
+mod calendar;
+mod config;
 mod database;
+mod dataset;
+mod departures;
+mod diff;
+mod feed;
 mod fetcher;
+mod frequency;
+mod http;
+mod index;
+mod inference;
+mod kalman;
 pub mod logger;
+mod matching;
+mod prediction;
 pub mod quadtree;
+mod recorder;
+mod source;
 pub mod store;
+mod timezone;
 pub mod utils;
+mod validation;
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
 async fn main() {
     dotenv().ok();
 
-    let (api_url, ip, port, secret, database_url) = get_env();
+    let (ip, port, secret, database_url) = get_env();
+    let feeds = get_feeds();
 
     let db = Db::new(&database_url).await;
     let db = match db {
@@ -25,33 +43,81 @@ async fn main() {
         Err(e) => panic!("Error connecting to database: {}", e),
     };
 
-    let store = Arc::new(store::Store::new(&secret, db));
-    match store.refresh_gtfs(&secret).await {
-        Ok(()) => {}
-        Err(e) => panic!("Error refreshing GTFS: {}", e),
-    };
+    let off_route = get_off_route_config();
+    let eta_model = get_eta_model();
+    let http = get_http_config();
+    let mut stores = Vec::new();
+    for feed in feeds {
+        let gtfs_source = match dataset::GtfsSource::new(&feed, http) {
+            Ok(gtfs_source) => gtfs_source,
+            Err(e) => panic!("{}", e),
+        };
+        let store = Arc::new(store::Store::new(
+            &secret,
+            db.clone(),
+            &feed,
+            gtfs_source,
+            off_route,
+            eta_model,
+        ));
+        match store.reload_gtfs().await {
+            Ok(_) => {}
+            Err(e) => panic!(
+                "Error refreshing GTFS of {}: {}",
+                feed.gtfs_url.as_deref().unwrap_or(&feed.gtfs_path),
+                e
+            ),
+        };
 
-    let thread_safe = store.clone();
-    tokio::spawn(async move {
-        let mut interval = interval(Duration::from_secs(5));
-        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
-        let thread_safe = thread_safe.clone();
-        let main_fetcher = fetcher::Fetcher::new(thread_safe.clone(), api_url);
-        loop {
-            interval.tick().await;
-            main_fetcher.fetch().await;
+        if feed.gtfs_url.is_some() {
+            let thread_safe = store.clone();
+            let period = Duration::from_secs(feed.gtfs_refresh_interval);
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+                interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = thread_safe.reload_gtfs().await {
+                        logger::critical("GTFS", &e);
+                    }
+                }
+            });
         }
-    });
 
-    api::init(ip, port, store.clone()).await;
-}
+        if store.get_eta_model() == prediction::PropagationModel::Historical {
+            let thread_safe = store.clone();
+            let days = get_eta_history_days();
+            tokio::spawn(async move {
+                let mut interval = interval(Duration::from_secs(3600));
+                loop {
+                    interval.tick().await;
+                    thread_safe.refresh_segment_times(days).await;
+                }
+            });
+        }
 
-fn get_env() -> (String, String, String, String, String) {
-    let api_url = match env::var("API_URL") {
-        Ok(key) => key,
-        Err(_) => panic!("No API_URL found in .env"),
-    };
+        let thread_safe = store.clone();
+        tokio::spawn(async move {
+            let mut interval = interval(Duration::from_secs_f64(feed.poll_interval));
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+            let thread_safe = thread_safe.clone();
+            let main_fetcher = match fetcher::Fetcher::new(thread_safe.clone(), &feed, http) {
+                Ok(fetcher) => fetcher,
+                Err(e) => panic!("{}", e),
+            };
+            loop {
+                interval.tick().await;
+                main_fetcher.fetch().await;
+            }
+        });
 
+        stores.push(store);
+    }
+
+    api::init(ip, port, stores).await;
+}
+
+fn get_env() -> (String, String, String, String) {
     let ip = match env::var("IP") {
         Ok(key) => key,
         Err(_) => panic!("No IP found in .env"),
@@ -72,5 +138,110 @@ fn get_env() -> (String, String, String, String, String) {
         Err(_) => panic!("No DATABASE_URL found in .env"),
     };
 
-    (api_url, ip, port, secret, database_url)
+    (ip, port, secret, database_url)
+}
+
+/// Feeds from the `FEEDS_CONFIG` file, or the single `API_URL` feed
+fn get_feeds() -> Vec<config::FeedConfig> {
+    if let Ok(path) = env::var("FEEDS_CONFIG") {
+        return match config::load_feeds(&path) {
+            Ok(feeds) => feeds,
+            Err(e) => panic!("{}", e),
+        };
+    }
+
+    let mut feed = match env::var("API_URL") {
+        Ok(api_url) => config::FeedConfig::legacy(api_url),
+        Err(_) => panic!("No API_URL or FEEDS_CONFIG found in .env"),
+    };
+    feed.record_dir = env::var("RECORD_DIR").ok();
+    feed.gtfs_url = env::var("GTFS_URL").ok();
+    if let Ok(interval) = env::var("GTFS_REFRESH_INTERVAL") {
+        match interval.parse() {
+            Ok(interval) if interval > 0 => feed.gtfs_refresh_interval = interval,
+            _ => panic!("Invalid GTFS_REFRESH_INTERVAL in .env"),
+        }
+    }
+
+    vec![feed]
+}
+
+fn get_off_route_config() -> store::OffRouteConfig {
+    let mut config = store::OffRouteConfig::default();
+
+    if let Ok(distance) = env::var("OFF_ROUTE_DISTANCE") {
+        match distance.parse() {
+            Ok(distance) => config.distance = distance,
+            Err(_) => panic!("Invalid OFF_ROUTE_DISTANCE in .env"),
+        }
+    }
+
+    if let Ok(fixes) = env::var("OFF_ROUTE_FIXES") {
+        match fixes.parse() {
+            Ok(fixes) => config.fixes = fixes,
+            Err(_) => panic!("Invalid OFF_ROUTE_FIXES in .env"),
+        }
+    }
+
+    config
+}
+
+fn get_http_config() -> http::HttpConfig {
+    let mut config = http::HttpConfig::default();
+
+    let seconds = |name: &str| match env::var(name) {
+        Ok(value) => match value.parse() {
+            Ok(value) => Some(Duration::from_secs(value)),
+            Err(_) => panic!("Invalid {} in .env", name),
+        },
+        Err(_) => None,
+    };
+
+    if let Some(timeout) = seconds("FETCH_CONNECT_TIMEOUT") {
+        config.connect_timeout = timeout;
+    }
+
+    if let Some(timeout) = seconds("FETCH_READ_TIMEOUT") {
+        config.read_timeout = timeout;
+    }
+
+    if let Some(cooldown) = seconds("BREAKER_COOLDOWN") {
+        config.breaker_cooldown = cooldown;
+    }
+
+    if let Ok(retries) = env::var("FETCH_RETRIES") {
+        match retries.parse() {
+            Ok(retries) => config.retries = retries,
+            Err(_) => panic!("Invalid FETCH_RETRIES in .env"),
+        }
+    }
+
+    if let Ok(failures) = env::var("BREAKER_FAILURES") {
+        match failures.parse() {
+            Ok(failures) => config.breaker_failures = failures,
+            Err(_) => panic!("Invalid BREAKER_FAILURES in .env"),
+        }
+    }
+
+    config
+}
+
+fn get_eta_model() -> prediction::PropagationModel {
+    match env::var("ETA_MODEL") {
+        Ok(model) => match model.parse() {
+            Ok(model) => model,
+            Err(e) => panic!("{}", e),
+        },
+        Err(_) => prediction::PropagationModel::Constant,
+    }
+}
+
+fn get_eta_history_days() -> i32 {
+    match env::var("ETA_HISTORY_DAYS") {
+        Ok(days) => match days.parse() {
+            Ok(days) => days,
+            Err(_) => panic!("Invalid ETA_HISTORY_DAYS in .env"),
+        },
+        Err(_) => 14,
+    }
 }
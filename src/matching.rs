@@ -0,0 +1,391 @@
+use gtfs_structures::Shape;
+
+/// Earth radius used by `utils::earth_distance` (m)
+const EARTH_RADIUS: f64 = 6378137.0;
+/// How far behind the previous match a vehicle may be matched (GPS jitter)
+const BACKWARD_TOLERANCE: f64 = 50.0;
+/// How far ahead of the previous match a vehicle may be matched
+const FORWARD_WINDOW: f64 = 2000.0;
+/// Above this distance a windowed match is discarded for a wider search
+const MAX_WINDOW_DISTANCE: f64 = 100.0;
+/// Fastest a vehicle is assumed to move along its shape, bounding the wider
+/// search (m/s)
+const MAX_SPEED: f64 = 30.0;
+/// Matches this much further than the nearest one are still candidates, so
+/// that a position on a street used both ways may pick either direction (m)
+const SNAP_TOLERANCE: f64 = 30.0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Match {
+    /// Distance along the shape of the matched point (m)
+    pub shape_dist_traveled: f64,
+    /// Distance between the position and the matched point (m)
+    pub distance: f64,
+    /// Distance along the shape the position projects to, before the floor
+    /// at the previous match (m)
+    pub projected: f64,
+}
+
+/// Match a vehicle position on a shape
+///
+/// With a previous match, the search is restricted to a window around it so
+/// that progress stays monotonic on loops and out-and-back routes. When the
+/// vehicle is too far from the window, the search is widened to as far as
+/// it could have gone in the `elapsed` seconds since the previous match,
+/// never behind. A vehicle not near the shape within that reach is matched
+/// at its nearest point in it, which the caller sees as off route.
+///
+/// Without a previous match, among the points of the shape about as near as
+/// the nearest one, the one closest to `hint` (the scheduled progress, the
+/// start of the shape by default) is kept.
+pub fn match_vehicle(
+    shape: &[Shape],
+    distances: &[f64],
+    latitude: f64,
+    longitude: f64,
+    previous: Option<f64>,
+    elapsed: u64,
+    hint: Option<f64>,
+) -> Option<Match> {
+    let previous = match previous {
+        Some(e) => e,
+        None => {
+            return seed(
+                shape,
+                distances,
+                latitude,
+                longitude,
+                0.0,
+                f64::INFINITY,
+                hint.unwrap_or(0.0),
+            )
+        }
+    };
+
+    let windowed = project(
+        shape,
+        distances,
+        latitude,
+        longitude,
+        previous - BACKWARD_TOLERANCE,
+        previous + FORWARD_WINDOW,
+    );
+
+    let reach = FORWARD_WINDOW.max(MAX_SPEED * elapsed as f64);
+    let mut matched = match windowed {
+        Some(e) if e.distance <= MAX_WINDOW_DISTANCE => e,
+        _ => seed(
+            shape,
+            distances,
+            latitude,
+            longitude,
+            previous - BACKWARD_TOLERANCE,
+            previous + reach,
+            previous,
+        )?,
+    };
+
+    //Jitter behind the previous match doesn't move the vehicle back
+    matched.shape_dist_traveled = matched.shape_dist_traveled.max(previous);
+    Some(matched)
+}
+
+/// Match each stop on the shape, in order, never going back along the shape
+pub fn match_stops(shape: &[Shape], distances: &[f64], stops: &[(f64, f64)]) -> Option<Vec<f64>> {
+    let mut previous = 0.0;
+    stops
+        .iter()
+        .map(|&(latitude, longitude)| {
+            let matched = seed(
+                shape,
+                distances,
+                latitude,
+                longitude,
+                previous,
+                f64::INFINITY,
+                previous,
+            )?;
+            previous = matched.shape_dist_traveled.max(previous);
+            Some(previous)
+        })
+        .collect()
+}
+
+/// Match on the shape between two distances along it, choosing the point
+/// closest to `hint` among the ones about as near as the nearest one
+fn seed(
+    shape: &[Shape],
+    distances: &[f64],
+    latitude: f64,
+    longitude: f64,
+    from: f64,
+    to: f64,
+    hint: f64,
+) -> Option<Match> {
+    let candidates = segments(shape, distances, latitude, longitude, from, to)?;
+    let nearest = candidates
+        .iter()
+        .map(|e| e.distance)
+        .min_by(|a, b| a.total_cmp(b))?;
+
+    candidates
+        .into_iter()
+        .filter(|e| e.distance <= nearest + SNAP_TOLERANCE)
+        .min_by(|a, b| {
+            (a.shape_dist_traveled - hint)
+                .abs()
+                .total_cmp(&(b.shape_dist_traveled - hint).abs())
+        })
+}
+
+/// Nearest point of the shape between two distances along it
+pub fn project(
+    shape: &[Shape],
+    distances: &[f64],
+    latitude: f64,
+    longitude: f64,
+    from: f64,
+    to: f64,
+) -> Option<Match> {
+    segments(shape, distances, latitude, longitude, from, to)?
+        .into_iter()
+        .min_by(|a, b| a.distance.total_cmp(&b.distance))
+}
+
+/// Nearest point of each segment of the shape between two distances along
+/// it
+fn segments(
+    shape: &[Shape],
+    distances: &[f64],
+    latitude: f64,
+    longitude: f64,
+    from: f64,
+    to: f64,
+) -> Option<Vec<Match>> {
+    if shape.len() != distances.len() || shape.is_empty() {
+        return None;
+    }
+
+    //Local equirectangular projection centered on the position (m)
+    let ky = EARTH_RADIUS * std::f64::consts::PI / 180.0;
+    let kx = ky * latitude.to_radians().cos();
+    let to_local = |point: &Shape| {
+        (
+            (point.longitude - longitude) * kx,
+            (point.latitude - latitude) * ky,
+        )
+    };
+
+    if shape.len() == 1 {
+        let (x, y) = to_local(&shape[0]);
+        return Some(vec![Match {
+            shape_dist_traveled: 0.0,
+            distance: x.hypot(y),
+            projected: 0.0,
+        }]);
+    }
+
+    let mut matches = Vec::new();
+    for i in 0..shape.len() - 1 {
+        if distances[i + 1] < from || distances[i] > to {
+            continue;
+        }
+
+        let (ax, ay) = to_local(&shape[i]);
+        let (bx, by) = to_local(&shape[i + 1]);
+        let (dx, dy) = (bx - ax, by - ay);
+        let length = dx * dx + dy * dy;
+
+        //Part of the segment inside the window
+        let segment_length = distances[i + 1] - distances[i];
+        let (t_low, t_high) = match segment_length > 0.0 {
+            true => (
+                ((from - distances[i]) / segment_length).clamp(0.0, 1.0),
+                ((to - distances[i]) / segment_length).clamp(0.0, 1.0),
+            ),
+            false => (0.0, 0.0),
+        };
+
+        let t = match length > 0.0 {
+            true => (-(ax * dx + ay * dy) / length).clamp(t_low, t_high),
+            false => 0.0,
+        };
+
+        let shape_dist_traveled = distances[i] + t * segment_length;
+        matches.push(Match {
+            shape_dist_traveled,
+            distance: (ax + t * dx).hypot(ay + t * dy),
+            projected: shape_dist_traveled,
+        });
+    }
+
+    Some(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::earth_distance;
+
+    /// Shape going east along a street and back on its other side, 5m apart
+    fn out_and_back() -> (Vec<Shape>, Vec<f64>) {
+        let points = [
+            (50.0, 4.0),
+            (50.0, 4.005),
+            (50.0, 4.01),
+            (50.00005, 4.01),
+            (50.00005, 4.005),
+            (50.00005, 4.0),
+        ];
+        let shape = points
+            .iter()
+            .enumerate()
+            .map(|(i, &(latitude, longitude))| Shape {
+                id: "shape".to_string(),
+                latitude,
+                longitude,
+                sequence: i,
+                dist_traveled: None,
+            })
+            .collect::<Vec<Shape>>();
+
+        let mut distances = vec![0.0];
+        for pair in points.windows(2) {
+            let last = *distances.last().unwrap();
+            distances.push(last + earth_distance(pair[0], pair[1]));
+        }
+        (shape, distances)
+    }
+
+    #[test]
+    fn first_match_prefers_start_of_shape() {
+        let (shape, distances) = out_and_back();
+        let half = distances[2];
+
+        //Nearer to the way back, still matched on the way out
+        let matched = match_vehicle(&shape, &distances, 50.00004, 4.002, None, 0, None).unwrap();
+        assert!(matched.shape_dist_traveled < half);
+    }
+
+    #[test]
+    fn first_match_follows_hint() {
+        let (shape, distances) = out_and_back();
+        let half = distances[2];
+        let end = *distances.last().unwrap();
+
+        let matched = match_vehicle(
+            &shape,
+            &distances,
+            50.00001,
+            4.002,
+            None,
+            0,
+            Some(end - 100.0),
+        )
+        .unwrap();
+        assert!(matched.shape_dist_traveled > half);
+
+        let matched =
+            match_vehicle(&shape, &distances, 50.00004, 4.002, None, 0, Some(0.0)).unwrap();
+        assert!(matched.shape_dist_traveled < half);
+    }
+
+    #[test]
+    fn progress_never_goes_back() {
+        let (shape, distances) = out_and_back();
+        let previous = distances[4] + 100.0;
+
+        //Jitter behind the previous match
+        let matched = match_vehicle(
+            &shape,
+            &distances,
+            50.00005,
+            4.0052,
+            Some(previous),
+            15,
+            None,
+        )
+        .unwrap();
+        assert_eq!(matched.shape_dist_traveled, previous);
+        assert!(matched.projected < previous);
+
+        //Far from the window, on the way out
+        let matched =
+            match_vehicle(&shape, &distances, 50.0, 4.008, Some(previous), 15, None).unwrap();
+        assert!(matched.shape_dist_traveled >= previous);
+        assert!(matched.distance > MAX_WINDOW_DISTANCE);
+    }
+
+    #[test]
+    fn wider_search_is_bounded_by_reach() {
+        //3km east, then back 120m further north
+        let points = [
+            (50.0, 4.0),
+            (50.0, 4.042),
+            (50.00108, 4.042),
+            (50.00108, 4.0),
+        ];
+        let shape = points
+            .iter()
+            .enumerate()
+            .map(|(i, &(latitude, longitude))| Shape {
+                id: "shape".to_string(),
+                latitude,
+                longitude,
+                sequence: i,
+                dist_traveled: None,
+            })
+            .collect::<Vec<Shape>>();
+        let mut distances = vec![0.0];
+        for pair in points.windows(2) {
+            let last = *distances.last().unwrap();
+            distances.push(last + earth_distance(pair[0], pair[1]));
+        }
+
+        //A detour near the way back, which can't be reached in 15s
+        let previous = 500.0;
+        let matched = match_vehicle(
+            &shape,
+            &distances,
+            50.00099,
+            4.0084,
+            Some(previous),
+            15,
+            None,
+        )
+        .unwrap();
+        assert!(matched.shape_dist_traveled < previous + FORWARD_WINDOW);
+        assert!(matched.distance > MAX_WINDOW_DISTANCE);
+
+        //but can be in 10 minutes
+        let matched = match_vehicle(
+            &shape,
+            &distances,
+            50.00099,
+            4.0084,
+            Some(previous),
+            600,
+            None,
+        )
+        .unwrap();
+        assert!(matched.shape_dist_traveled > distances[2]);
+        assert!(matched.distance < MAX_WINDOW_DISTANCE);
+    }
+
+    #[test]
+    fn stops_are_matched_in_order() {
+        let (shape, distances) = out_and_back();
+        let stops = [
+            (50.00002, 4.0),
+            (50.00004, 4.005),
+            (50.00002, 4.01),
+            (50.00001, 4.005),
+            (50.00003, 4.0),
+        ];
+
+        let matched = match_stops(&shape, &distances, &stops).unwrap();
+        assert!(matched[1] < distances[2]);
+        assert!(matched[3] > distances[2]);
+        assert!(matched.windows(2).all(|e| e[0] < e[1]));
+    }
+}
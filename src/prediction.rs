@@ -0,0 +1,205 @@
+use std::{collections::HashMap, str::FromStr};
+
+use gtfs_structures::{Gtfs, StopTime, TimepointType};
+use serde::Serialize;
+
+/// How the current delay is carried over to the following stops
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PropagationModel {
+    /// Same delay at every stop
+    Constant,
+    /// Early vehicles wait at timepoints, late ones recover the scheduled dwell
+    Timepoint,
+    /// Run times between stops taken from the recorded history
+    Historical,
+}
+
+impl FromStr for PropagationModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "constant" => Ok(Self::Constant),
+            "timepoint" => Ok(Self::Timepoint),
+            "historical" => Ok(Self::Historical),
+            _ => Err(format!("Unknown propagation model: {}", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct StopPrediction {
+    pub stop_id: String,
+    pub stop_sequence: u16,
+    pub scheduled_arrival: Option<i64>,
+    pub scheduled_departure: Option<i64>,
+    pub predicted_arrival: i64,
+    pub predicted_departure: i64,
+    pub delay: f64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TripPrediction {
+    pub bus: String,
+    pub trip_id: String,
+    /// Service date of the run (`YYYYMMDD`)
+    pub start_date: String,
+    /// Departure of a frequency-based trip (`HH:MM:SS`)
+    pub start_time: Option<String>,
+    pub line: String,
+    pub stops: Vec<StopPrediction>,
+}
+
+/// Mean observed time between the departures of two consecutive stops (s)
+#[derive(Default)]
+pub struct SegmentTimes {
+    times: HashMap<(String, String), f64>,
+}
+
+impl SegmentTimes {
+    /// Aggregate run times recorded per (trip, next stop) into run times
+    /// per pair of stops, weighted by the number of observations
+    pub fn build(gtfs: &Gtfs, rows: Vec<(String, usize, f64, i64)>) -> Self {
+        let mut sums: HashMap<(String, String), (f64, i64)> = HashMap::new();
+        for (trip_id, next_stop, run_time, count) in rows {
+            let stops = match gtfs.trips.get(&trip_id) {
+                Some(trip) => &trip.stop_times,
+                None => continue,
+            };
+
+            if next_stop == 0 || next_stop >= stops.len() {
+                continue;
+            }
+
+            let key = (
+                stops[next_stop - 1].stop.id.clone(),
+                stops[next_stop].stop.id.clone(),
+            );
+            let sum = sums.entry(key).or_insert((0.0, 0));
+            sum.0 += run_time * count as f64;
+            sum.1 += count;
+        }
+
+        let times = sums
+            .into_iter()
+            .filter(|(_, (_, count))| *count > 0)
+            .map(|(key, (total, count))| (key, total / count as f64))
+            .collect();
+
+        Self { times }
+    }
+
+    pub fn get(&self, from: &str, to: &str) -> Option<f64> {
+        self.times.get(&(from.to_string(), to.to_string())).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.times.len()
+    }
+}
+
+/// Predict arrival and departure of every stop from `next_stop` onwards,
+/// starting from the delay at `next_stop`
+pub fn predict(
+    stops: &[StopTime],
+    next_stop: usize,
+    delay: f64,
+    service_day_start: i64,
+    model: PropagationModel,
+    history: &SegmentTimes,
+) -> Vec<StopPrediction> {
+    let mut predictions: Vec<StopPrediction> = Vec::with_capacity(stops.len());
+    let mut delay = delay;
+
+    for (i, stop) in stops.iter().enumerate().skip(next_stop) {
+        let (arrival, departure) = match (stop.arrival_time, stop.departure_time) {
+            (Some(arrival), Some(departure)) => (arrival, departure),
+            (Some(arrival), None) => (arrival, arrival),
+            (None, Some(departure)) => (departure, departure),
+            (None, None) => continue,
+        };
+        let arrival = service_day_start + arrival as i64;
+        let departure = service_day_start + departure as i64;
+        let dwell = departure - arrival;
+
+        let previous = match i > next_stop {
+            true => predictions.last(),
+            false => None,
+        };
+
+        let predicted_arrival = match (model, previous) {
+            (PropagationModel::Historical, Some(previous)) => {
+                //The previous stop may be an earlier one than i - 1, if that
+                //one has no time
+                match history.get(&previous.stop_id, &stop.stop.id) {
+                    Some(run_time) => {
+                        let predicted = previous.predicted_departure + run_time.round() as i64;
+                        (predicted - dwell).max(previous.predicted_departure)
+                    }
+                    None => arrival + delay.round() as i64,
+                }
+            }
+            _ => arrival + delay.round() as i64,
+        };
+
+        let predicted_departure = match (model, &stop.timepoint) {
+            (PropagationModel::Timepoint, TimepointType::Exact) => departure.max(predicted_arrival),
+            _ => predicted_arrival + dwell,
+        };
+
+        delay = (predicted_departure - departure) as f64;
+        predictions.push(StopPrediction {
+            delay: (predicted_arrival - arrival) as f64,
+            stop_id: stop.stop.id.clone(),
+            stop_sequence: stop.stop_sequence,
+            scheduled_arrival: stop.arrival_time.map(|e| service_day_start + e as i64),
+            scheduled_departure: stop.departure_time.map(|e| service_day_start + e as i64),
+            predicted_arrival,
+            predicted_departure,
+        });
+    }
+
+    predictions
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use gtfs_structures::Stop;
+
+    use super::*;
+
+    fn stop_time(id: &str, time: Option<u32>) -> StopTime {
+        StopTime {
+            stop: Arc::new(Stop {
+                id: id.to_string(),
+                ..Default::default()
+            }),
+            arrival_time: time,
+            departure_time: time,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn historical_run_time_from_previous_predicted_stop() {
+        //b has no time, so c follows the prediction of a
+        let stops = [
+            stop_time("a", Some(0)),
+            stop_time("b", None),
+            stop_time("c", Some(600)),
+        ];
+        let history = SegmentTimes {
+            times: HashMap::from([
+                (("a".to_string(), "c".to_string()), 300.0),
+                (("b".to_string(), "c".to_string()), 60.0),
+            ]),
+        };
+
+        let predictions = predict(&stops, 0, 0.0, 0, PropagationModel::Historical, &history);
+        assert_eq!(predictions.len(), 2);
+        assert_eq!(predictions[1].stop_id, "c");
+        assert_eq!(predictions[1].predicted_arrival, 300);
+    }
+}
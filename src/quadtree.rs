@@ -1,4 +1,14 @@
-use std::{collections::VecDeque, fmt::Debug};
+use std::{
+    cmp::Ordering,
+    collections::{vec_deque, BinaryHeap, VecDeque},
+    fmt::Debug,
+    str::FromStr,
+};
+
+use crate::utils::earth_distance;
+
+/// Earth radius used by `utils::earth_distance` (m)
+const EARTH_RADIUS: f64 = 6378137.0;
 
 #[derive(Debug, Clone, Copy)]
 pub struct Extent {
@@ -18,7 +28,7 @@ pub struct QuadTree<T: Clone + Debug> {
     extent: Extent,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Coordinate {
     x: f64,
     y: f64,
@@ -28,9 +38,113 @@ impl Coordinate {
     pub fn new(x: f64, y: f64) -> Self {
         Self { x, y }
     }
+
+    pub fn x(&self) -> f64 {
+        self.x
+    }
+
+    pub fn y(&self) -> f64 {
+        self.y
+    }
+
+    /// Distance in meters, x being the longitude and y the latitude
+    pub fn distance(&self, other: &Coordinate) -> f64 {
+        earth_distance((self.y, self.x), (other.y, other.x))
+    }
+}
+
+/// Depth-first iterator over the values of a tree with their coordinate
+pub struct Iter<'a, T: Clone + Debug> {
+    stack: Vec<&'a QuadTree<T>>,
+    current: Option<(&'a Coordinate, vec_deque::Iter<'a, T>)>,
+}
+
+impl<'a, T: Clone + Debug> Iterator for Iter<'a, T> {
+    type Item = (&'a Coordinate, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((coord, values)) = &mut self.current {
+                if let Some(value) = values.next() {
+                    return Some((*coord, value));
+                }
+            }
+
+            let node = self.stack.pop()?;
+            self.current = node
+                .value
+                .as_ref()
+                .map(|(data, coord)| (coord, data.iter()));
+            self.stack.extend(node.children());
+        }
+    }
+}
+
+impl<'a, T: Clone + Debug> IntoIterator for &'a QuadTree<T> {
+    type Item = (&'a Coordinate, &'a T);
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+enum Entry<'a, T: Clone + Debug> {
+    Node(&'a QuadTree<T>),
+    Value(&'a T),
+}
+
+/// Heap entry, ordered so that the closest candidate is popped first
+struct Candidate<'a, T: Clone + Debug> {
+    distance: f64,
+    entry: Entry<'a, T>,
+}
+
+impl<T: Clone + Debug> PartialEq for Candidate<'_, T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.distance == other.distance
+    }
+}
+
+impl<T: Clone + Debug> Eq for Candidate<'_, T> {}
+
+impl<T: Clone + Debug> PartialOrd for Candidate<'_, T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: Clone + Debug> Ord for Candidate<'_, T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.distance.total_cmp(&self.distance)
+    }
+}
+
+/// Parse `x_low,y_low,x_high,y_high` (min_lon,min_lat,max_lon,max_lat)
+impl FromStr for Extent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|e| e.trim().parse::<f64>().ok())
+            .collect::<Option<Vec<f64>>>();
+
+        match values.as_deref() {
+            Some(&[x_low, y_low, x_high, y_high]) if x_low <= x_high && y_low <= y_high => {
+                Ok(Self::new(x_low, y_low, x_high, y_high))
+            }
+            _ => Err(format!("Invalid extent: {}", s)),
+        }
+    }
 }
 
 impl Extent {
+    /// Whole world in longitude/latitude
+    pub fn world() -> Self {
+        Self::new(-180.0, -90.0, 180.0, 90.0)
+    }
+
     pub fn new(x_low: f64, y_low: f64, x_high: f64, y_high: f64) -> Self {
         Self {
             x_low,
@@ -53,6 +167,39 @@ impl Extent {
             && self.y_high >= other.y_low
     }
 
+    /// Lower bound of the distance in meters between the coordinate and any
+    /// point of the extent, x being the longitude and y the latitude
+    ///
+    /// Two points can't be closer than their latitude difference, nor than
+    /// the distance to the great circle of the closest edge meridian
+    pub fn min_distance(&self, coord: &Coordinate) -> f64 {
+        let d_lat = if coord.y < self.y_low {
+            self.y_low - coord.y
+        } else if coord.y > self.y_high {
+            coord.y - self.y_high
+        } else {
+            0.0
+        };
+
+        let d_lon = if coord.x < self.x_low {
+            self.x_low - coord.x
+        } else if coord.x > self.x_high {
+            coord.x - self.x_high
+        } else {
+            0.0
+        };
+
+        let lat_bound = d_lat.to_radians() * EARTH_RADIUS;
+        let lon_bound = match d_lon < 90.0 {
+            true => {
+                let cross = coord.y.to_radians().cos() * d_lon.to_radians().sin();
+                cross.clamp(0.0, 1.0).asin() * EARTH_RADIUS
+            }
+            false => 0.0,
+        };
+
+        lat_bound.max(lon_bound)
+    }
 
     pub fn quadrant(&self, coord: &Coordinate) -> usize {
         let x = coord.x;
@@ -189,6 +336,98 @@ impl<T: Clone + Debug> QuadTree<T> {
         true
     }
 
+    /// Remove the values stored at the coordinate that match the predicate,
+    /// returns how many were removed
+    ///
+    /// Nodes left with at most one coordinate under them are collapsed back
+    /// into their parent, so the tree doesn't keep growing as values move
+    pub fn remove<F: Fn(&T) -> bool>(&mut self, coord: &Coordinate, predicate: F) -> usize {
+        self.remove_node(coord, &predicate)
+    }
+
+    fn remove_node(&mut self, coord: &Coordinate, predicate: &dyn Fn(&T) -> bool) -> usize {
+        if !self.extent.contains(coord) {
+            return 0;
+        }
+
+        if self.has_children {
+            let child = match self.extent.quadrant(coord) {
+                0 => self.bot_left.as_mut(),
+                1 => self.bot_right.as_mut(),
+                2 => self.top_left.as_mut(),
+                3 => self.top_right.as_mut(),
+                _ => panic!("Invalid quadrant"), //can't happen
+            };
+            let removed = child.map_or(0, |child| child.remove_node(coord, predicate));
+            if removed > 0 {
+                self.collapse();
+            }
+            return removed;
+        }
+
+        let data = match &mut self.value {
+            Some((data, value_coord)) if value_coord == coord => data,
+            _ => return 0,
+        };
+
+        let before = data.len();
+        data.retain(|value| !predicate(value));
+        let removed = before - data.len();
+        if data.is_empty() {
+            self.value = None;
+        }
+
+        removed
+    }
+
+    /// Merge the children back into this node when they are leaves holding
+    /// a single coordinate at most
+    fn collapse(&mut self) {
+        if self.children().any(|child| child.has_children)
+            || self
+                .children()
+                .filter(|child| child.value.is_some())
+                .count()
+                > 1
+        {
+            return;
+        }
+
+        self.value = [
+            &mut self.bot_left,
+            &mut self.bot_right,
+            &mut self.top_left,
+            &mut self.top_right,
+        ]
+        .into_iter()
+        .filter_map(|child| child.take())
+        .find_map(|child| child.value);
+        self.has_children = false;
+    }
+
+    /// Remove every value, keeping the extent
+    pub fn clear(&mut self) {
+        *self = QuadTree::new(self.extent);
+    }
+
+    /// Number of values in the tree
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// Every value with its coordinate, in no particular order
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            stack: vec![self],
+            current: None,
+        }
+    }
+
+    /// Values whose coordinate is inside the extent
     pub fn find_bbox(&self, extent: &Extent) -> Vec<&T> {
         let mut result = Vec::new();
         let mut stack = Vec::new();
@@ -196,22 +435,87 @@ impl<T: Clone + Debug> QuadTree<T> {
 
         while let Some(node) = stack.pop() {
             if node.extent.intersects(extent) {
-                if let Some((data, _)) = &node.value {
-                    result.extend(data.iter());
+                if let Some((data, coord)) = &node.value {
+                    if extent.contains(coord) {
+                        result.extend(data.iter());
+                    }
                 }
 
-                if node.has_children {
-                    if let Some(child) = &node.bot_left {
-                        stack.push(child);
-                    }
-                    if let Some(child) = &node.bot_right {
-                        stack.push(child);
+                stack.extend(node.children());
+            }
+        }
+
+        result
+    }
+
+    /// Values within `meters` of the coordinate (x: longitude, y: latitude)
+    pub fn find_within_radius(&self, coord: &Coordinate, meters: f64) -> Vec<(&T, f64)> {
+        let dlat = (meters / EARTH_RADIUS).to_degrees();
+        let dlon = dlat / coord.y.to_radians().cos().max(f64::EPSILON);
+        let extent = Extent::new(
+            coord.x - dlon,
+            coord.y - dlat,
+            coord.x + dlon,
+            coord.y + dlat,
+        );
+
+        let mut result = Vec::new();
+        let mut stack = Vec::new();
+        stack.push(self);
+
+        while let Some(node) = stack.pop() {
+            if node.extent.intersects(&extent) {
+                if let Some((data, value_coord)) = &node.value {
+                    let distance = coord.distance(value_coord);
+                    if distance <= meters {
+                        result.extend(data.iter().map(|value| (value, distance)));
                     }
-                    if let Some(child) = &node.top_left {
-                        stack.push(child);
+                }
+
+                stack.extend(node.children());
+            }
+        }
+
+        result
+    }
+
+    /// The `k` closest values to the coordinate (x: longitude, y: latitude),
+    /// closest first, with their distance in meters
+    ///
+    /// Nodes are visited best-first, ordered by a lower bound of the
+    /// distance to their extent, so the search stops as soon as the `k`
+    /// values are found
+    pub fn nearest_k(&self, coord: &Coordinate, k: usize) -> Vec<(&T, f64)> {
+        let mut result = Vec::with_capacity(k);
+        let mut heap = BinaryHeap::new();
+        heap.push(Candidate {
+            distance: self.extent.min_distance(coord),
+            entry: Entry::Node(self),
+        });
+
+        while let Some(Candidate { distance, entry }) = heap.pop() {
+            if result.len() >= k {
+                break;
+            }
+
+            match entry {
+                Entry::Value(value) => result.push((value, distance)),
+                Entry::Node(node) => {
+                    if let Some((data, value_coord)) = &node.value {
+                        let distance = coord.distance(value_coord);
+                        for value in data {
+                            heap.push(Candidate {
+                                distance,
+                                entry: Entry::Value(value),
+                            });
+                        }
                     }
-                    if let Some(child) = &node.top_right {
-                        stack.push(child);
+
+                    for child in node.children() {
+                        heap.push(Candidate {
+                            distance: child.extent.min_distance(coord),
+                            entry: Entry::Node(child),
+                        });
                     }
                 }
             }
@@ -220,6 +524,18 @@ impl<T: Clone + Debug> QuadTree<T> {
         result
     }
 
+    fn children(&self) -> impl Iterator<Item = &QuadTree<T>> {
+        [
+            &self.bot_left,
+            &self.bot_right,
+            &self.top_left,
+            &self.top_right,
+        ]
+        .into_iter()
+        .flatten()
+        .map(|child| child.as_ref())
+    }
+
     pub fn print(&self) {
         println!("{:?}", self.extent);
         if let Some(child) = &self.bot_left {
@@ -242,3 +558,192 @@ impl<T: Clone + Debug> QuadTree<T> {
         println!();
     }
 }
+
+impl<T: Clone + Debug + PartialEq> QuadTree<T> {
+    /// Move a value from one coordinate to another, returns false if it
+    /// wasn't stored at the old coordinate (it is inserted anyway)
+    pub fn move_value(&mut self, old: &Coordinate, new: &Coordinate, value: T) -> bool {
+        let found = self.remove(old, |e| *e == value) > 0;
+        self.insert(new, value);
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    fn random_coordinate(rng: &mut StdRng, extent: &Extent) -> Coordinate {
+        Coordinate::new(
+            rng.gen_range(extent.x_low..=extent.x_high),
+            rng.gen_range(extent.y_low..=extent.y_high),
+        )
+    }
+
+    /// Random values in the extent, some of them sharing a coordinate
+    fn random_values(rng: &mut StdRng, extent: &Extent, count: usize) -> Vec<(Coordinate, usize)> {
+        let mut values: Vec<(Coordinate, usize)> = Vec::with_capacity(count);
+        for id in 0..count {
+            let coord = match values.is_empty() || rng.gen_bool(0.9) {
+                true => random_coordinate(rng, extent),
+                false => values[rng.gen_range(0..values.len())].0.clone(),
+            };
+            values.push((coord, id));
+        }
+        values
+    }
+
+    fn build(values: &[(Coordinate, usize)]) -> QuadTree<usize> {
+        let mut tree = QuadTree::new(Extent::world());
+        for (coord, id) in values {
+            tree.insert(coord, *id);
+        }
+        tree
+    }
+
+    fn sorted<'a>(ids: impl IntoIterator<Item = &'a usize>) -> Vec<usize> {
+        let mut ids = ids.into_iter().copied().collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Compare every search against a linear scan of the values
+    fn check(
+        tree: &QuadTree<usize>,
+        values: &[(Coordinate, usize)],
+        rng: &mut StdRng,
+        extent: &Extent,
+    ) {
+        assert_eq!(tree.len(), values.len());
+        assert_eq!(
+            sorted(tree.iter().map(|(_, id)| id)),
+            sorted(values.iter().map(|(_, id)| id))
+        );
+
+        let coords = values
+            .iter()
+            .map(|(coord, id)| (id, coord))
+            .collect::<HashMap<_, _>>();
+
+        for _ in 0..50 {
+            let a = random_coordinate(rng, extent);
+            let b = random_coordinate(rng, extent);
+            let bbox = Extent::new(a.x.min(b.x), a.y.min(b.y), a.x.max(b.x), a.y.max(b.y));
+            let expected = values
+                .iter()
+                .filter(|(coord, _)| bbox.contains(coord))
+                .map(|(_, id)| id);
+            assert_eq!(sorted(tree.find_bbox(&bbox)), sorted(expected));
+
+            let center = random_coordinate(rng, extent);
+            let distance =
+                |coord: &Coordinate| earth_distance((center.y, center.x), (coord.y, coord.x));
+
+            let meters = rng.gen_range(0.0..50_000.0);
+            let found = tree.find_within_radius(&center, meters);
+            for (id, found_distance) in &found {
+                assert!((found_distance - distance(coords[*id])).abs() < 1e-6);
+            }
+            let expected = values
+                .iter()
+                .filter(|(coord, _)| distance(coord) <= meters)
+                .map(|(_, id)| id);
+            assert_eq!(sorted(found.iter().map(|(id, _)| *id)), sorted(expected));
+
+            //Ties make the ids ambiguous, the distances aren't
+            let k = rng.gen_range(0..20);
+            let found = tree.nearest_k(&center, k);
+            let mut expected = values
+                .iter()
+                .map(|(coord, _)| distance(coord))
+                .collect::<Vec<_>>();
+            expected.sort_by(f64::total_cmp);
+            expected.truncate(k);
+            assert_eq!(found.len(), expected.len());
+            for ((id, found_distance), expected) in found.iter().zip(&expected) {
+                assert!((found_distance - expected).abs() < 1e-6);
+                assert!((distance(coords[*id]) - found_distance).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn searches_match_linear_scan() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for extent in [Extent::new(2.5, 49.5, 6.4, 51.5), Extent::world()] {
+            let values = random_values(&mut rng, &extent, 2000);
+            let tree = build(&values);
+            check(&tree, &values, &mut rng, &extent);
+        }
+    }
+
+    #[test]
+    fn searches_match_linear_scan_after_moves() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let extent = Extent::new(4.2, 50.3, 4.6, 50.6);
+        let mut values = random_values(&mut rng, &extent, 1000);
+        let mut tree = build(&values);
+
+        for _ in 0..5 {
+            for (coord, id) in values.iter_mut() {
+                if rng.gen_bool(0.5) {
+                    let new = random_coordinate(&mut rng, &extent);
+                    assert!(tree.move_value(coord, &new, *id));
+                    *coord = new;
+                }
+            }
+            check(&tree, &values, &mut rng, &extent);
+        }
+
+        //Not stored there, inserted anyway
+        let coord = random_coordinate(&mut rng, &extent);
+        assert!(!tree.move_value(&Coordinate::new(0.0, 0.0), &coord, values.len()));
+        values.push((coord, values.len()));
+        check(&tree, &values, &mut rng, &extent);
+    }
+
+    #[test]
+    fn remove_collapses_tree() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let extent = Extent::new(4.2, 50.3, 4.6, 50.6);
+        let mut values = random_values(&mut rng, &extent, 500);
+        let mut tree = build(&values);
+
+        //Wrong coordinate or predicate
+        let (coord, id) = values[0].clone();
+        assert_eq!(tree.remove(&Coordinate::new(0.0, 0.0), |e| *e == id), 0);
+        assert_eq!(tree.remove(&coord, |_| false), 0);
+
+        while !values.is_empty() {
+            let (coord, id) = values.swap_remove(rng.gen_range(0..values.len()));
+            assert_eq!(tree.remove(&coord, |e| *e == id), 1);
+            if values.len().is_multiple_of(100) {
+                check(&tree, &values, &mut rng, &extent);
+            }
+        }
+
+        assert!(tree.is_empty());
+        assert!(!tree.has_children);
+        assert!(tree.value.is_none());
+    }
+
+    #[test]
+    fn remove_keeps_single_coordinate_in_root() {
+        let mut tree = QuadTree::new(Extent::world());
+        let a = Coordinate::new(4.35, 50.85);
+        let b = Coordinate::new(4.36, 50.85);
+        tree.insert(&a, 1);
+        tree.insert(&a, 2);
+        tree.insert(&b, 3);
+        assert!(tree.has_children);
+
+        assert_eq!(tree.remove(&b, |_| true), 1);
+        assert!(!tree.has_children);
+        assert_eq!(tree.value.as_ref().map(|(_, coord)| coord), Some(&a));
+        assert_eq!(sorted(tree.find_bbox(&Extent::world())), vec![1, 2]);
+    }
+}
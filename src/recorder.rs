@@ -0,0 +1,90 @@
+use std::{
+    fs::OpenOptions,
+    io::{ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use flate2::{write::GzEncoder, Compression};
+use tokio::sync::mpsc::{self, error::TrySendError, Sender};
+
+use crate::logger;
+
+/// Feeds waiting to be written before new ones are dropped
+const QUEUE_SIZE: usize = 64;
+
+/// Archives every fetched feed in `<dir>/<YYYY-MM-DDTHH>.pb.gz` (UTC)
+///
+/// Each feed is appended as its own gzip member holding the fetch time in
+/// milliseconds (8 bytes), the length of the feed (4 bytes) and the feed,
+/// so an archive cut by a crash only loses its last record. Feeds are
+/// written in order by a single task, off the fetch loop
+pub struct Recorder {
+    sender: Sender<(DateTime<Utc>, Vec<u8>)>,
+}
+
+impl Recorder {
+    pub fn new(dir: &str, service: &str) -> Result<Self, String> {
+        std::fs::create_dir_all(dir).map_err(|e| format!("Error creating {}: {}", dir, e))?;
+
+        let (sender, mut receiver) = mpsc::channel::<(DateTime<Utc>, Vec<u8>)>(QUEUE_SIZE);
+        let (dir, service) = (PathBuf::from(dir), service.to_string());
+        tokio::task::spawn_blocking(move || {
+            while let Some((time, feed)) = receiver.blocking_recv() {
+                if let Err(e) = write_record(&dir, time, &feed) {
+                    logger::critical(&service, &e);
+                }
+            }
+        });
+
+        Ok(Self { sender })
+    }
+
+    /// Queue the feed for writing
+    pub fn record(&self, time: DateTime<Utc>, feed: Vec<u8>) -> Result<(), String> {
+        match self.sender.try_send((time, feed)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err("Recorder queue full, feed dropped".to_string()),
+            Err(TrySendError::Closed(_)) => Err("Recorder stopped, feed dropped".to_string()),
+        }
+    }
+}
+
+fn write_record(dir: &Path, time: DateTime<Utc>, feed: &[u8]) -> Result<(), String> {
+    let path = dir.join(format!("{}.pb.gz", time.format("%Y-%m-%dT%H")));
+
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| format!("Error opening {}: {}", path.display(), e))?;
+
+    let mut encoder = GzEncoder::new(file, Compression::default());
+    encoder
+        .write_all(&time.timestamp_millis().to_be_bytes())
+        .and_then(|_| encoder.write_all(&(feed.len() as u32).to_be_bytes()))
+        .and_then(|_| encoder.write_all(feed))
+        .and_then(|_| encoder.finish().map(|_| ()))
+        .map_err(|e| format!("Error writing {}: {}", path.display(), e))
+}
+
+/// Next record of an archive read through a `MultiGzDecoder`, `None` at the
+/// end of the archive (or at a record cut by a crash)
+pub fn read_record<R: Read>(reader: &mut R) -> Result<Option<(i64, Vec<u8>)>, String> {
+    let mut header = [0u8; 12];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(format!("Error reading record: {}", e)),
+    }
+
+    let time = i64::from_be_bytes(header[..8].try_into().unwrap());
+    let len = u32::from_be_bytes(header[8..].try_into().unwrap()) as usize;
+
+    let mut feed = vec![0u8; len];
+    match reader.read_exact(&mut feed) {
+        Ok(()) => Ok(Some((time, feed))),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(format!("Error reading record: {}", e)),
+    }
+}
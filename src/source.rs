@@ -0,0 +1,74 @@
+use std::{path::Path, sync::Arc};
+
+use async_trait::async_trait;
+
+use crate::http::HttpClient;
+
+mod directory;
+mod file;
+mod http;
+mod replay;
+
+pub use directory::DirectorySource;
+pub use file::FileSource;
+pub use http::HttpSource;
+pub use replay::ReplaySource;
+
+/// Raw GTFS-RT feed returned by a source
+pub struct Snapshot {
+    pub body: Vec<u8>,
+    /// False when the source returned the same feed as the previous call
+    pub modified: bool,
+}
+
+/// Where a feed's realtime data comes from
+#[async_trait]
+pub trait RealtimeSource: Send + Sync {
+    /// Current feed of the source
+    async fn fetch(&self) -> Result<Snapshot, String>;
+
+    /// Url or path, for logs
+    fn name(&self) -> &str;
+
+    /// Whether the source plays recorded feeds, which are then processed at
+    /// the time of their header instead of the current time
+    fn replays(&self) -> bool {
+        false
+    }
+
+    /// Whether another feed is already due, to be fetched within the same
+    /// poll
+    fn pending(&self) -> bool {
+        false
+    }
+}
+
+/// Source matching the url: `http(s)://` for a server, `file://` for a
+/// local `.pb` file or a directory of snapshots, `replay://` for the
+/// archives of a recorder (`replay:///path?speed=10`)
+pub fn from_url(url: &str, client: Arc<HttpClient>) -> Result<Box<dyn RealtimeSource>, String> {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        return Ok(Box::new(HttpSource::new(url, client)));
+    }
+
+    if let Some(path) = url.strip_prefix("replay://") {
+        let (path, speed) = match path.split_once("?speed=") {
+            Some((path, speed)) => match speed.parse::<f64>() {
+                Ok(speed) if speed > 0.0 => (path, speed),
+                _ => return Err(format!("Invalid replay speed: {}", url)),
+            },
+            None => (path, 1.0),
+        };
+        return Ok(Box::new(ReplaySource::new(path, speed)));
+    }
+
+    let path = match url.strip_prefix("file://") {
+        Some(path) => path,
+        None => return Err(format!("Unsupported realtime url: {}", url)),
+    };
+
+    match Path::new(path).is_dir() {
+        true => Ok(Box::new(DirectorySource::new(path))),
+        false => Ok(Box::new(FileSource::new(path))),
+    }
+}
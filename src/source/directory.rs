@@ -0,0 +1,95 @@
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+
+use super::{RealtimeSource, Snapshot};
+
+/// Directory of snapshots named so that they sort chronologically (e.g.
+/// `1731283200.pb`), one snapshot is played per fetch
+///
+/// The directory is listed again on every fetch so snapshots added while
+/// running are picked up, the last one is kept once all have been played
+pub struct DirectorySource {
+    path: String,
+    /// Name and content of the last snapshot played
+    current: Mutex<Option<(String, Vec<u8>)>>,
+}
+
+impl DirectorySource {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            current: Mutex::new(None),
+        }
+    }
+
+    async fn snapshots(&self) -> Result<Vec<String>, String> {
+        let mut entries = tokio::fs::read_dir(&self.path)
+            .await
+            .map_err(|e| format!("Error listing {}: {}", self.path, e))?;
+
+        let mut names = Vec::new();
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| format!("Error listing {}: {}", self.path, e))?
+        {
+            if !entry.file_type().await.is_ok_and(|e| e.is_file()) {
+                continue;
+            }
+            if let Ok(name) = entry.file_name().into_string() {
+                if name.ends_with(".pb") {
+                    names.push(name);
+                }
+            }
+        }
+
+        names.sort();
+        Ok(names)
+    }
+}
+
+#[async_trait]
+impl RealtimeSource for DirectorySource {
+    async fn fetch(&self) -> Result<Snapshot, String> {
+        let snapshots = self.snapshots().await?;
+        let current = self.current.lock().unwrap().clone();
+
+        let next = match &current {
+            Some((name, _)) => snapshots.iter().find(|e| *e > name),
+            None => snapshots.first(),
+        };
+
+        let name = match (next, current) {
+            (Some(name), _) => name,
+            (None, Some((_, body))) => {
+                return Ok(Snapshot {
+                    body,
+                    modified: false,
+                })
+            }
+            (None, None) => return Err(format!("No snapshot in {}", self.path)),
+        };
+
+        let path = std::path::Path::new(&self.path).join(name);
+        let body = tokio::fs::read(&path)
+            .await
+            .map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+
+        let mut current = self.current.lock().unwrap();
+        *current = Some((name.clone(), body.clone()));
+
+        Ok(Snapshot {
+            body,
+            modified: true,
+        })
+    }
+
+    fn name(&self) -> &str {
+        &self.path
+    }
+
+    fn replays(&self) -> bool {
+        true
+    }
+}
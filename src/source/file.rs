@@ -0,0 +1,58 @@
+use std::{sync::Mutex, time::SystemTime};
+
+use async_trait::async_trait;
+
+use super::{RealtimeSource, Snapshot};
+
+/// Local `.pb` file, re-read when its modification time or size changes
+pub struct FileSource {
+    path: String,
+    /// Modification time, size and content of the last read
+    cache: Mutex<Option<(SystemTime, u64, Vec<u8>)>>,
+}
+
+impl FileSource {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            cache: Mutex::new(None),
+        }
+    }
+}
+
+#[async_trait]
+impl RealtimeSource for FileSource {
+    async fn fetch(&self) -> Result<Snapshot, String> {
+        let metadata = tokio::fs::metadata(&self.path)
+            .await
+            .map_err(|e| format!("Error reading {}: {}", self.path, e))?;
+        let modified_at = metadata
+            .modified()
+            .map_err(|e| format!("Error reading {}: {}", self.path, e))?;
+
+        if let Some((time, len, body)) = &*self.cache.lock().unwrap() {
+            if *time == modified_at && *len == metadata.len() {
+                return Ok(Snapshot {
+                    body: body.clone(),
+                    modified: false,
+                });
+            }
+        }
+
+        let body = tokio::fs::read(&self.path)
+            .await
+            .map_err(|e| format!("Error reading {}: {}", self.path, e))?;
+
+        let mut cache = self.cache.lock().unwrap();
+        *cache = Some((modified_at, metadata.len(), body.clone()));
+
+        Ok(Snapshot {
+            body,
+            modified: true,
+        })
+    }
+
+    fn name(&self) -> &str {
+        &self.path
+    }
+}
@@ -0,0 +1,57 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+
+use super::{RealtimeSource, Snapshot};
+use crate::http::{HttpClient, Response};
+
+/// GTFS-RT served over http, fetched conditionally when the server sends
+/// validators
+pub struct HttpSource {
+    url: String,
+    client: Arc<HttpClient>,
+    /// Last response, kept only if it had validators
+    cache: Mutex<Option<Response>>,
+}
+
+impl HttpSource {
+    pub fn new(url: &str, client: Arc<HttpClient>) -> Self {
+        Self {
+            url: url.to_string(),
+            client,
+            cache: Mutex::new(None),
+        }
+    }
+}
+
+#[async_trait]
+impl RealtimeSource for HttpSource {
+    async fn fetch(&self) -> Result<Snapshot, String> {
+        let cached = self.cache.lock().unwrap().clone();
+        let validators = cached.as_ref().map(|cached| &cached.validators);
+
+        match self.client.get(&self.url, validators).await? {
+            Some(resp) => {
+                let mut cache = self.cache.lock().unwrap();
+                *cache = match resp.validators.is_empty() {
+                    true => None,
+                    false => Some(resp.clone()),
+                };
+
+                Ok(Snapshot {
+                    body: resp.body,
+                    modified: true,
+                })
+            }
+            //Validators are only sent along with a cached body
+            None => Ok(Snapshot {
+                body: cached.map(|cached| cached.body).unwrap_or_default(),
+                modified: false,
+            }),
+        }
+    }
+
+    fn name(&self) -> &str {
+        &self.url
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use flate2::read::MultiGzDecoder;
+
+use super::{RealtimeSource, Snapshot};
+use crate::recorder;
+
+type Archive = MultiGzDecoder<BufReader<File>>;
+
+/// Plays back the archives of a `Recorder`, `speed` times faster than they
+/// were recorded
+///
+/// Each fetch plays the next record once it is due, and records already
+/// due are `pending` so that a poll plays all of them, however slow the
+/// poll interval is compared to the replay
+pub struct ReplaySource {
+    path: String,
+    speed: f64,
+    /// Shared with the blocking task reading the archives
+    state: Arc<Mutex<ReplayState>>,
+}
+
+#[derive(Default)]
+struct ReplayState {
+    /// Name and reader of the archive being played
+    archive: Option<(String, Archive)>,
+    /// Next record, read ahead to know when it is due
+    next: Option<(i64, Vec<u8>)>,
+    /// Error reading ahead, returned by the next fetch
+    error: Option<String>,
+    /// Last record played
+    current: Option<Vec<u8>>,
+    /// When the replay started and the time of its first record
+    start: Option<(Instant, i64)>,
+}
+
+impl ReplaySource {
+    pub fn new(path: &str, speed: f64) -> Self {
+        Self {
+            path: path.to_string(),
+            speed,
+            state: Arc::new(Mutex::new(ReplayState::default())),
+        }
+    }
+}
+
+impl ReplayState {
+    /// Play the next record if it is due, reading the archives in `path`
+    fn play(&mut self, path: &str, speed: f64) -> Result<Snapshot, String> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        if self.next.is_none() {
+            self.next = self.read_next(path)?;
+        }
+
+        if !self.due(speed) {
+            return match &self.current {
+                Some(body) => Ok(Snapshot {
+                    body: body.clone(),
+                    modified: false,
+                }),
+                None => Err(format!("No record in {}", path)),
+            };
+        }
+
+        let (_, body) = self.next.take().unwrap();
+        self.current = Some(body.clone());
+        //Read ahead to know if the following record is due
+        match self.read_next(path) {
+            Ok(next) => self.next = next,
+            Err(e) => self.error = Some(e),
+        }
+
+        Ok(Snapshot {
+            body,
+            modified: true,
+        })
+    }
+
+    /// Whether the next record is due at the replay clock, which starts on
+    /// the first record
+    fn due(&mut self, speed: f64) -> bool {
+        let time = match &self.next {
+            Some((time, _)) => *time,
+            None => return false,
+        };
+
+        let (started, first) = *self.start.get_or_insert((Instant::now(), time));
+        let due = Duration::from_millis(((time - first).max(0) as f64 / speed) as u64);
+        started.elapsed() >= due
+    }
+
+    fn read_next(&mut self, path: &str) -> Result<Option<(i64, Vec<u8>)>, String> {
+        loop {
+            if let Some((_, archive)) = &mut self.archive {
+                if let Some(record) = recorder::read_record(archive)? {
+                    return Ok(Some(record));
+                }
+            }
+
+            let after = self.archive.as_ref().map(|(name, _)| name.clone());
+            match next_archive(path, after.as_deref())? {
+                Some(archive) => self.archive = Some(archive),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+/// First archive after the one being played, archives are named so that
+/// they sort chronologically
+fn next_archive(path: &str, after: Option<&str>) -> Result<Option<(String, Archive)>, String> {
+    let entries = std::fs::read_dir(path).map_err(|e| format!("Error listing {}: {}", path, e))?;
+
+    let name = entries
+        .flatten()
+        .flat_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.ends_with(".pb.gz"))
+        .filter(|name| after.is_none_or(|after| name.as_str() > after))
+        .min();
+
+    let name = match name {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+
+    let file_path = Path::new(path).join(&name);
+    let file = File::open(&file_path)
+        .map_err(|e| format!("Error opening {}: {}", file_path.display(), e))?;
+
+    Ok(Some((name, MultiGzDecoder::new(BufReader::new(file)))))
+}
+
+#[async_trait]
+impl RealtimeSource for ReplaySource {
+    async fn fetch(&self) -> Result<Snapshot, String> {
+        //Reading and decompressing the archives blocks
+        let (path, speed, state) = (self.path.clone(), self.speed, self.state.clone());
+        tokio::task::spawn_blocking(move || state.lock().unwrap().play(&path, speed))
+            .await
+            .map_err(|e| format!("Error replaying {}: {}", self.path, e))?
+    }
+
+    fn name(&self) -> &str {
+        &self.path
+    }
+
+    fn replays(&self) -> bool {
+        true
+    }
+
+    fn pending(&self) -> bool {
+        self.state.lock().unwrap().due(self.speed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    /// Directory holding an archive of the feeds, recorded at the times (ms)
+    fn archive(name: &str, records: &[(i64, &[u8])]) -> String {
+        let dir = std::env::temp_dir().join(format!("replay-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let file = File::create(dir.join("2026-10-18T10.pb.gz")).unwrap();
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        for (time, feed) in records {
+            encoder.write_all(&time.to_be_bytes()).unwrap();
+            encoder
+                .write_all(&(feed.len() as u32).to_be_bytes())
+                .unwrap();
+            encoder.write_all(feed).unwrap();
+        }
+        encoder.finish().unwrap();
+
+        dir.to_string_lossy().to_string()
+    }
+
+    #[tokio::test]
+    async fn plays_every_due_record() {
+        let path = archive("due", &[(0, b"a"), (1_000, b"b"), (2_000, b"c")]);
+        let source = ReplaySource::new(&path, 1_000_000.0);
+
+        for feed in [b"a", b"b", b"c"] {
+            let snapshot = source.fetch().await.unwrap();
+            assert!(snapshot.modified);
+            assert_eq!(snapshot.body, feed);
+        }
+        assert!(!source.pending());
+
+        let snapshot = source.fetch().await.unwrap();
+        assert!(!snapshot.modified);
+        assert_eq!(snapshot.body, b"c");
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn read_ahead_error_is_returned_by_next_fetch() {
+        let path = archive("error", &[(0, b"a")]);
+        std::fs::write(
+            Path::new(&path).join("2026-10-18T11.pb.gz"),
+            b"this is not a gzip archive at all",
+        )
+        .unwrap();
+        let source = ReplaySource::new(&path, 1_000_000.0);
+
+        assert_eq!(source.fetch().await.unwrap().body, b"a");
+        assert!(source.fetch().await.is_err());
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn waits_for_records_not_due() {
+        let path = archive("wait", &[(0, b"a"), (1_000, b"b"), (3_600_000, b"c")]);
+        let source = ReplaySource::new(&path, 10_000.0);
+
+        assert!(source.fetch().await.unwrap().modified);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert!(source.pending());
+        assert_eq!(source.fetch().await.unwrap().body, b"b");
+
+        //Due in 360ms
+        assert!(!source.pending());
+        let snapshot = source.fetch().await.unwrap();
+        assert!(!snapshot.modified);
+        assert_eq!(snapshot.body, b"b");
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -1,23 +1,82 @@
 use base64::{engine::general_purpose, Engine as _};
 use flate2::{write::GzEncoder, Compression};
+use protobuf::Message;
 use std::io::Write;
 use std::{
-    collections::VecDeque,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     sync::{Arc, RwLock},
 };
 
-use dashmap::DashMap;
-use gtfs_structures::{Gtfs, GtfsReader};
+use dashmap::{mapref::one::Ref, DashMap};
+use gtfs_structures::StopTime;
+use rayon::prelude::*;
 use serde::Serialize;
 
+use crate::config::FeedConfig;
 use crate::database::Db;
+use crate::dataset::{Dataset, GtfsSource};
+use crate::diff::GtfsDiff;
+use crate::feed;
+use crate::frequency::{self, EvaluationMode};
+use crate::http::FetchStatus;
+use crate::inference::Position;
+use crate::kalman::KalmanFilter;
 use crate::logger;
+use crate::prediction::{self, PropagationModel, SegmentTimes, TripPrediction};
+use crate::quadtree::{Coordinate, Extent, QuadTree};
+use crate::timezone;
+use crate::utils;
+
+/// Trip id, start date and start time of a run: a trip may run on two
+/// service dates at once past midnight, or several times a day
+pub type TripRun = (String, Option<String>, Option<String>);
+pub type TripUpdates = DashMap<TripRun, TripUpdate>;
+
+/// Service dates whose observed trips are kept
+const OBSERVED_DAYS: usize = 3;
 
 pub struct BusSpeed {
     pub expire: usize,
     pub speeds: VecDeque<f32>,
     pub speed_average: f32,
 }
+/// State kept between two fetches of the same vehicle
+pub struct VehicleState {
+    pub expire: usize,
+    pub trip_id: String,
+    pub timestamp: u64,
+    pub shape_dist_traveled: Option<f64>,
+    /// Time of the fix the progress was last matched at
+    pub progress_at: u64,
+    pub off_route_fixes: usize,
+    pub on_route_fixes: usize,
+    pub is_out: bool,
+    pub out_since: Option<u64>,
+    pub rejoined_at: Option<u64>,
+    /// Last fixes, oldest first
+    pub positions: VecDeque<Position>,
+    /// Progress along the shape of the current trip
+    pub filter: Option<KalmanFilter>,
+}
+
+/// Off-route detection settings
+#[derive(Debug, Clone, Copy)]
+pub struct OffRouteConfig {
+    /// Distance to the shape above which a fix is off route (m)
+    pub distance: f64,
+    /// Consecutive fixes needed to leave or rejoin the route
+    pub fixes: usize,
+}
+
+impl Default for OffRouteConfig {
+    fn default() -> Self {
+        Self {
+            distance: 150.0,
+            fixes: 3,
+        }
+    }
+}
+
 #[derive(Serialize, Debug)]
 pub struct Bus {
     pub timestamp: u64,
@@ -25,6 +84,16 @@ pub struct Bus {
     pub line: String,
     pub line_id: String,
     pub trip_id: String,
+    /// How sure the inference of the trip is (0 to 1), `None` when the
+    /// feed gives the trip
+    pub trip_confidence: Option<f64>,
+    /// Service date of the trip (`YYYYMMDD`)
+    pub start_date: Option<String>,
+    /// Whether the trip is not scheduled on its service date
+    pub unscheduled: bool,
+    /// Departure of a frequency-based trip (`HH:MM:SS`)
+    pub start_time: Option<String>,
+    pub mode: EvaluationMode,
     pub agency_id: String,
     pub latitude: f32,
     pub longitude: f32,
@@ -33,9 +102,27 @@ pub struct Bus {
     pub average_count: usize,
     pub next_stop: usize,
     pub theorical_stop: usize,
+    pub shape_dist_traveled: f64,
+    /// Filtered speed (m/s) and acceleration (m/s²) along the shape
+    pub estimated_speed: f64,
+    pub acceleration: f64,
+    /// Whether the last position was rejected as a GPS jump
+    pub position_rejected: bool,
     pub remaining_distance: f64,
-    pub delay: f64,
+    /// Delay against the schedule (s), `None` until the progress of the
+    /// vehicle along its trip is known
+    pub delay: Option<f64>,
+    pub reported_delay: Option<f64>,
+    /// Scheduled running time to the vehicle ahead, in headway mode (s)
+    pub headway: Option<f64>,
+    pub scheduled_headway: Option<u32>,
+    /// `headway - scheduled_headway` (s), positive when the vehicle lags
+    /// too far behind the one ahead
+    pub headway_adherence: Option<f64>,
+    pub distance_to_shape: f64,
     pub is_out: bool,
+    pub out_since: Option<u64>,
+    pub rejoined_at: Option<u64>,
 }
 
 impl Default for Bus {
@@ -46,6 +133,11 @@ impl Default for Bus {
             line: "?".to_string(),
             line_id: "?".to_string(),
             trip_id: "?".to_string(),
+            trip_confidence: None,
+            start_date: None,
+            unscheduled: false,
+            start_time: None,
+            mode: EvaluationMode::Schedule,
             agency_id: "?".to_string(),
             latitude: 0.0,
             longitude: 0.0,
@@ -54,9 +146,20 @@ impl Default for Bus {
             average_count: 0,
             next_stop: 0,
             theorical_stop: 0,
+            shape_dist_traveled: 0.0,
+            estimated_speed: 0.0,
+            acceleration: 0.0,
+            position_rejected: false,
             remaining_distance: 0.0,
-            delay: 0.0,
+            delay: None,
+            reported_delay: None,
+            headway: None,
+            scheduled_headway: None,
+            headway_adherence: None,
+            distance_to_shape: 0.0,
             is_out: false,
+            out_since: None,
+            rejoined_at: None,
         }
     }
 }
@@ -91,6 +194,26 @@ impl Bus {
         self.trip_id = trip_id.to_string();
     }
 
+    pub fn set_trip_confidence(&mut self, trip_confidence: f64) {
+        self.trip_confidence = Some(trip_confidence);
+    }
+
+    pub fn set_start_date(&mut self, start_date: &str) {
+        self.start_date = Some(start_date.to_string());
+    }
+
+    pub fn set_unscheduled(&mut self, unscheduled: bool) {
+        self.unscheduled = unscheduled;
+    }
+
+    pub fn set_start_time(&mut self, start_time: &str) {
+        self.start_time = Some(start_time.to_string());
+    }
+
+    pub fn set_mode(&mut self, mode: EvaluationMode) {
+        self.mode = mode;
+    }
+
     pub fn set_speed(&mut self, speed: f32) {
         self.speed = speed;
     }
@@ -111,45 +234,376 @@ impl Bus {
         self.theorical_stop = theorical_stop;
     }
 
+    pub fn set_shape_dist_traveled(&mut self, shape_dist_traveled: f64) {
+        self.shape_dist_traveled = shape_dist_traveled;
+    }
+
+    pub fn set_estimated_speed(&mut self, estimated_speed: f64) {
+        self.estimated_speed = estimated_speed;
+    }
+
+    pub fn set_acceleration(&mut self, acceleration: f64) {
+        self.acceleration = acceleration;
+    }
+
+    pub fn set_position_rejected(&mut self, position_rejected: bool) {
+        self.position_rejected = position_rejected;
+    }
+
     pub fn set_remaining_distance(&mut self, remaining_distance: f64) {
         self.remaining_distance = remaining_distance;
     }
 
     pub fn set_delay(&mut self, delay: f64) {
-        self.delay = delay;
+        self.delay = Some(delay);
+    }
+
+    pub fn set_reported_delay(&mut self, reported_delay: f64) {
+        self.reported_delay = Some(reported_delay);
+    }
+
+    pub fn set_headway(&mut self, headway: f64) {
+        self.headway = Some(headway);
+    }
+
+    pub fn set_scheduled_headway(&mut self, scheduled_headway: u32) {
+        self.scheduled_headway = Some(scheduled_headway);
+    }
+
+    pub fn set_headway_adherence(&mut self, headway_adherence: f64) {
+        self.headway_adherence = Some(headway_adherence);
+    }
+
+    pub fn set_distance_to_shape(&mut self, distance_to_shape: f64) {
+        self.distance_to_shape = distance_to_shape;
     }
 
     pub fn set_is_out(&mut self, is_out: bool) {
         self.is_out = is_out;
     }
+
+    pub fn set_out_since(&mut self, out_since: Option<u64>) {
+        self.out_since = out_since;
+    }
+
+    pub fn set_rejoined_at(&mut self, rejoined_at: Option<u64>) {
+        self.rejoined_at = rejoined_at;
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct StopTimeUpdate {
+    pub stop_sequence: Option<u32>,
+    pub stop_id: Option<String>,
+    pub arrival_delay: Option<i32>,
+    pub arrival_time: Option<i64>,
+    pub departure_delay: Option<i32>,
+    pub departure_time: Option<i64>,
+    pub schedule_relationship: String,
+}
+
+impl StopTimeUpdate {
+    /// Delay announced for this stop, arrival first then departure
+    pub fn delay(&self) -> Option<i32> {
+        self.arrival_delay.or(self.departure_delay)
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TripUpdate {
+    pub id: String,
+    pub trip_id: String,
+    pub route_id: Option<String>,
+    pub start_date: Option<String>,
+    pub start_time: Option<String>,
+    pub vehicle_id: Option<String>,
+    pub timestamp: Option<u64>,
+    pub delay: Option<i32>,
+    pub schedule_relationship: String,
+    pub stop_time_updates: Vec<StopTimeUpdate>,
+}
+
+impl TripUpdate {
+    fn key(&self) -> TripRun {
+        (
+            self.trip_id.clone(),
+            self.start_date.clone(),
+            self.start_time.clone(),
+        )
+    }
+
+    /// Delay reported for a stop of the trip, given by its index in the
+    /// stop times of the trip.
+    ///
+    /// Following the GTFS-RT propagation rules, a stop without its own update
+    /// inherits the delay of the closest preceding updated stop. Updates
+    /// without a `stop_sequence` are placed by their `stop_id`, and skipped
+    /// stops don't give their delay to the following ones.
+    pub fn delay_at(&self, stops: &[StopTime], stop: usize) -> Option<i32> {
+        let mut delay = None;
+        let mut from = 0;
+        for update in &self.stop_time_updates {
+            let index = match (update.stop_sequence, update.stop_id.as_deref()) {
+                (Some(sequence), _) => stops
+                    .iter()
+                    .position(|e| e.stop_sequence as u32 == sequence),
+                //A stop served twice is the first occurrence after the
+                //previous update
+                (None, Some(stop_id)) => stops[from..]
+                    .iter()
+                    .position(|e| e.stop.id == stop_id)
+                    .map(|e| e + from),
+                (None, None) => None,
+            };
+            let index = match index {
+                Some(e) => e,
+                None => continue,
+            };
+            if index > stop {
+                break;
+            }
+            from = index;
+
+            match update.schedule_relationship.as_str() {
+                "NO_DATA" => delay = None,
+                "SKIPPED" => {}
+                _ => {
+                    if let Some(e) = update.delay() {
+                        delay = Some(e);
+                    }
+                }
+            }
+        }
+
+        delay.or(self.delay)
+    }
+
+    pub fn is_canceled(&self) -> bool {
+        self.schedule_relationship == "CANCELED"
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Translation {
+    pub language: Option<String>,
+    pub text: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ActivePeriod {
+    pub start: Option<u64>,
+    pub end: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct InformedEntity {
+    pub agency_id: Option<String>,
+    pub route_id: Option<String>,
+    pub trip_id: Option<String>,
+    pub stop_id: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Alert {
+    pub id: String,
+    pub cause: String,
+    pub effect: String,
+    pub severity: String,
+    pub header: Vec<Translation>,
+    pub description: Vec<Translation>,
+    pub url: Vec<Translation>,
+    pub active_periods: Vec<ActivePeriod>,
+    pub informed_entities: Vec<InformedEntity>,
+}
+
+impl Alert {
+    /// An alert without active period is always active
+    pub fn is_active(&self, timestamp: u64) -> bool {
+        self.active_periods.is_empty()
+            || self.active_periods.iter().any(|period| {
+                period.start.is_none_or(|start| start <= timestamp)
+                    && period.end.is_none_or(|end| timestamp <= end)
+            })
+    }
+}
+
+/// Buses of the last fetch, with a spatial index on their position
+pub struct Vehicles {
+    pub buses: VecDeque<Bus>,
+    ids: HashMap<String, usize>,
+}
+
+impl Vehicles {
+    pub fn new(buses: VecDeque<Bus>) -> Self {
+        let ids = buses
+            .iter()
+            .enumerate()
+            .map(|(i, bus)| (bus.id.clone(), i))
+            .collect();
+
+        Self { buses, ids }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Bus> {
+        self.ids.get(id).map(|&i| &self.buses[i])
+    }
+}
+
+/// Vehicle ids indexed by position, moved in place between two fetches
+/// instead of being rebuilt
+pub struct VehicleTree {
+    tree: QuadTree<String>,
+    positions: HashMap<String, Coordinate>,
+}
+
+impl VehicleTree {
+    pub fn new() -> Self {
+        Self {
+            tree: QuadTree::new(Extent::world()),
+            positions: HashMap::new(),
+        }
+    }
+
+    pub fn update(&mut self, buses: &VecDeque<Bus>) {
+        let world = Extent::world();
+        let mut seen = HashSet::new();
+
+        for bus in buses {
+            let coord = Coordinate::new(bus.longitude as f64, bus.latitude as f64);
+            if !world.contains(&coord) {
+                continue;
+            }
+
+            seen.insert(bus.id.as_str());
+            match self.positions.insert(bus.id.clone(), coord.clone()) {
+                Some(old) if old == coord => {}
+                Some(old) => {
+                    self.tree.move_value(&old, &coord, bus.id.clone());
+                }
+                None => {
+                    self.tree.insert(&coord, bus.id.clone());
+                }
+            }
+        }
+
+        let tree = &mut self.tree;
+        self.positions.retain(|id, coord| {
+            if seen.contains(id.as_str()) {
+                return true;
+            }
+            tree.remove(coord, |e| e == id);
+            false
+        });
+    }
+
+    /// Ids of the vehicles inside the extent (longitude/latitude)
+    pub fn find_bbox(&self, extent: &Extent) -> Vec<String> {
+        self.tree.find_bbox(extent).into_iter().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+}
+
+impl Default for VehicleTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Trip update of a run of a trip, or of the trip when the feed doesn't
+/// give its start date
+pub fn find_trip_update<'a>(
+    trip_updates: &'a TripUpdates,
+    trip_id: &str,
+    start_date: Option<&str>,
+    start_time: Option<&str>,
+) -> Option<Ref<'a, TripRun, TripUpdate>> {
+    let key = |start_date: Option<&str>| {
+        (
+            trip_id.to_string(),
+            start_date.map(str::to_string),
+            start_time.map(str::to_string),
+        )
+    };
+    trip_updates
+        .get(&key(start_date))
+        .or_else(|| trip_updates.get(&key(None)))
 }
 
 pub struct Store {
     buses_speed: Arc<DashMap<String, Arc<RwLock<BusSpeed>>>>, //meant to be precise not cpu cache friendly
+    vehicles_state: Arc<DashMap<String, Arc<RwLock<VehicleState>>>>,
     raw: RwLock<Vec<u8>>,
-    gtfs: Arc<RwLock<Gtfs>>,
+    enriched: RwLock<Vec<u8>>,
+    dataset: RwLock<Arc<Dataset>>,
+    reload: tokio::sync::Mutex<()>,
     secret: String,
     json: RwLock<Vec<u8>>,
+    vehicles: RwLock<Arc<Vehicles>>,
+    vehicle_tree: RwLock<VehicleTree>,
+    trip_updates: RwLock<Arc<TripUpdates>>,
+    alerts: RwLock<Vec<Alert>>,
+    predictions: Arc<DashMap<String, TripPrediction>>,
+    segment_times: RwLock<Arc<SegmentTimes>>,
+    off_route: OffRouteConfig,
+    eta_model: PropagationModel,
     db: Arc<Db>,
+    namespace: String,
+    gtfs_source: GtfsSource,
+    fetch_status: RwLock<FetchStatus>,
+    /// Trips vehicles were seen on, per service date
+    observed_trips: RwLock<BTreeMap<String, HashSet<String>>>,
 }
 
 impl Store {
-    pub fn new(secret: &str, db: Arc<Db>) -> Self {
+    pub fn new(
+        secret: &str,
+        db: Arc<Db>,
+        feed: &FeedConfig,
+        gtfs_source: GtfsSource,
+        off_route: OffRouteConfig,
+        eta_model: PropagationModel,
+    ) -> Self {
         Self {
             raw: RwLock::new(Vec::new()),
-            gtfs: Arc::new(RwLock::new(Gtfs::default())),
+            enriched: RwLock::new(Vec::new()),
+            dataset: RwLock::new(Arc::new(Dataset::default())),
+            reload: tokio::sync::Mutex::new(()),
             secret: secret.to_string(),
             buses_speed: Arc::new(DashMap::new()),
+            vehicles_state: Arc::new(DashMap::new()),
             json: RwLock::new(compress_string("[]").unwrap()),
+            vehicles: RwLock::new(Arc::new(Vehicles::new(VecDeque::new()))),
+            vehicle_tree: RwLock::new(VehicleTree::new()),
+            trip_updates: RwLock::new(Arc::new(DashMap::new())),
+            alerts: RwLock::new(Vec::new()),
+            predictions: Arc::new(DashMap::new()),
+            segment_times: RwLock::new(Arc::new(SegmentTimes::default())),
+            off_route,
+            eta_model,
             db,
+            namespace: feed.namespace.clone(),
+            gtfs_source,
+            fetch_status: RwLock::new(FetchStatus::default()),
+            observed_trips: RwLock::new(BTreeMap::new()),
         }
     }
 
-    pub fn get_gtfs(&self) -> Arc<RwLock<Gtfs>> {
-        self.gtfs.clone()
+    pub fn namespace(&self) -> &str {
+        &self.namespace
     }
 
-    pub async fn refresh_gtfs(&self, secret: &String) -> Result<(), String> {
+    pub fn dataset(&self) -> Arc<Dataset> {
+        self.dataset.read().unwrap().clone()
+    }
+
+    pub async fn refresh_gtfs(&self, secret: &String) -> Result<bool, String> {
         if self.secret.is_empty() {
             logger::fine("FETCHER", "No secret, not refreshing GTFS");
             return Err((&"Internal error").to_string());
@@ -160,28 +614,59 @@ impl Store {
             return Err((&"Internal error").to_string());
         }
 
-        let raw_gtfs = self.gtfs.clone();
+        self.reload_gtfs().await
+    }
+
+    /// Load the GTFS and build its indexes off to the side, then swap them
+    /// in at once, the previous dataset is kept if anything fails
+    ///
+    /// Returns false if the GTFS didn't change
+    pub async fn reload_gtfs(&self) -> Result<bool, String> {
+        let _reload = self.reload.lock().await;
+
+        let previous = self.dataset();
+        let dataset = match self.gtfs_source.load(&previous).await? {
+            Some(dataset) => Arc::new(dataset),
+            None => {
+                logger::fine("GTFS", "GTFS unchanged");
+                return Ok(false);
+            }
+        };
+
+        {
+            let mut self_dataset = self.dataset.write().unwrap();
+            *self_dataset = dataset.clone();
+        }
+
+        //Nothing to compare with on the first load
+        if !previous.gtfs.trips.is_empty() {
+            self.refresh_gtfs_diff(previous, dataset).await;
+        }
 
-        let gtfs = tokio::task::spawn_blocking(move || {
-            logger::fine("FETCHER", "Refresh GTFS");
-            let gtfs = match GtfsReader::default()
-                .read_stop_times(true)
-                .read_shapes(true)
-                .read_from_path("gtfs")
-            {
-                Ok(gtfs) => gtfs,
-                Err(_) => panic!("Error loading GTFS"),
+        Ok(true)
+    }
+
+    async fn refresh_gtfs_diff(&self, previous: Arc<Dataset>, dataset: Arc<Dataset>) {
+        let diff =
+            match tokio::task::spawn_blocking(move || GtfsDiff::build(&previous, &dataset)).await {
+                Ok(diff) => diff,
+                Err(e) => {
+                    logger::critical("GTFS", &format!("Error comparing GTFS: {}", e));
+                    return;
+                }
             };
-            logger::fine("FETCHER", "Loaded GTFS");
-            gtfs
-        })
-        .await
-        .unwrap();
+        logger::info("GTFS", &format!("GTFS changes: {}", diff.summary()));
 
-        let mut raw_gtfs = raw_gtfs.write().unwrap();
-        *raw_gtfs = gtfs;
+        if let Err(e) = self.db.insert_gtfs_diff(&self.namespace, &diff).await {
+            logger::critical("DATABASE", &format!("Error inserting GTFS diff: {}", e));
+        }
+    }
 
-        Ok(())
+    pub async fn gtfs_diffs(&self, limit: i64) -> Result<Vec<String>, String> {
+        self.db
+            .gtfs_diffs(&self.namespace, limit)
+            .await
+            .map_err(|e| format!("Error loading GTFS diffs: {}", e))
     }
 
     pub async fn refresh_raw(&self, raw: Vec<u8>) {
@@ -189,6 +674,104 @@ impl Store {
         *self_raw = raw;
     }
 
+    pub async fn refresh_enriched(&self, buses: &VecDeque<Bus>) {
+        let message = feed::build_feed(buses, &self.dataset().gtfs, &self.predictions);
+
+        let enriched = match message.write_to_bytes() {
+            Ok(enriched) => enriched,
+            Err(e) => {
+                logger::critical("FEED", &format!("Error encoding feed: {}", e));
+                return;
+            }
+        };
+
+        let mut self_enriched = self.enriched.write().unwrap();
+        *self_enriched = enriched;
+    }
+
+    pub async fn refresh_predictions(&self, buses: &VecDeque<Bus>) {
+        let history = self.segment_times.read().unwrap().clone();
+        let dataset = self.dataset();
+        let gtfs = &dataset.gtfs;
+
+        let predictions = buses
+            .par_iter()
+            .flat_map(|bus| {
+                //Buses whose progress is unknown have nothing to predict from
+                let delay = bus.delay?;
+                let trip = gtfs.trips.get(&bus.trip_id)?;
+                if bus.next_stop >= trip.stop_times.len() {
+                    return None;
+                }
+                let service_date = bus.start_date.as_deref().and_then(utils::parse_date)?;
+                let offset = bus
+                    .start_time
+                    .as_deref()
+                    .and_then(utils::parse_time)
+                    .and_then(|e| frequency::offset(trip, e))
+                    .unwrap_or(0);
+
+                let stops = prediction::predict(
+                    &trip.stop_times,
+                    bus.next_stop,
+                    delay,
+                    timezone::service_day_start(&dataset.timezone, service_date) + offset,
+                    self.eta_model,
+                    &history,
+                );
+
+                Some(TripPrediction {
+                    bus: bus.id.clone(),
+                    trip_id: bus.trip_id.clone(),
+                    start_date: service_date.format("%Y%m%d").to_string(),
+                    start_time: bus.start_time.clone(),
+                    line: bus.line.clone(),
+                    stops,
+                })
+            })
+            .collect::<Vec<TripPrediction>>();
+
+        self.predictions.clear();
+        for prediction in predictions {
+            self.predictions.insert(prediction.bus.clone(), prediction);
+        }
+    }
+
+    pub async fn refresh_segment_times(&self, days: i32) {
+        let rows = match self.db.segment_run_times(&self.namespace, days).await {
+            Ok(rows) => rows,
+            Err(e) => {
+                logger::critical("DATABASE", &format!("Error loading run times: {}", e));
+                return;
+            }
+        };
+
+        let segment_times = SegmentTimes::build(&self.dataset().gtfs, rows);
+        logger::fine(
+            "PREDICTION",
+            &format!("Loaded run times of {} segments", segment_times.len()),
+        );
+
+        let mut self_segment_times = self.segment_times.write().unwrap();
+        *self_segment_times = Arc::new(segment_times);
+    }
+
+    /// Swap the trip updates at once, readers never see them half filled
+    pub async fn refresh_trip_updates(&self, trip_updates: Vec<TripUpdate>) {
+        let trip_updates = trip_updates
+            .into_iter()
+            .map(|trip_update| (trip_update.key(), trip_update))
+            .collect::<TripUpdates>();
+
+        let mut self_trip_updates = self.trip_updates.write().unwrap();
+        *self_trip_updates = Arc::new(trip_updates);
+    }
+
+    pub async fn refresh_alerts(&self, alerts: Vec<Alert>) {
+        let mut self_alerts = self.alerts.write().unwrap();
+        *self_alerts = alerts;
+    }
+
     pub async fn refresh(&self, buses: &VecDeque<Bus>) {
         let mut json_current = self.json.write().unwrap();
         let json_message = serde_json::to_string(buses).unwrap_or("[]".to_string());
@@ -203,8 +786,17 @@ impl Store {
         *json_current = json_message;
     }
 
+    pub async fn refresh_vehicles(&self, buses: VecDeque<Bus>) {
+        let mut tree = self.vehicle_tree.write().unwrap();
+        tree.update(&buses);
+
+        let new_vehicles = Arc::new(Vehicles::new(buses));
+        let mut vehicles = self.vehicles.write().unwrap();
+        *vehicles = new_vehicles;
+    }
+
     pub async fn refresh_db(&self, buses: &VecDeque<Bus>) {
-        match self.db.insert_buses(buses).await {
+        match self.db.insert_buses(&self.namespace, buses).await {
             Ok(_) => {}
             Err(e) => {
                 logger::critical("DATABASE", &format!("Error inserting buses: {}", e));
@@ -212,17 +804,91 @@ impl Store {
         };
     }
 
+    /// Keeps the trips of the buses, for the last `OBSERVED_DAYS` service
+    /// dates
+    pub async fn refresh_observed_trips(&self, buses: &VecDeque<Bus>) {
+        let mut observed_trips = self.observed_trips.write().unwrap();
+        for bus in buses {
+            if let Some(start_date) = &bus.start_date {
+                observed_trips
+                    .entry(start_date.clone())
+                    .or_default()
+                    .insert(bus.trip_id.clone());
+            }
+        }
+
+        while observed_trips.len() > OBSERVED_DAYS {
+            observed_trips.pop_first();
+        }
+    }
+
+    pub async fn refresh_fetch_status(&self, status: FetchStatus) {
+        let mut fetch_status = self.fetch_status.write().unwrap();
+        *fetch_status = status;
+    }
+
     pub async fn retrieve_json(&self) -> Vec<u8> {
         self.json.read().unwrap().clone()
     }
 
+    pub async fn vehicles(&self) -> Arc<Vehicles> {
+        self.vehicles.read().unwrap().clone()
+    }
+
+    /// Ids of the vehicles inside the extent (longitude/latitude)
+    pub async fn find_vehicles(&self, extent: &Extent) -> Vec<String> {
+        self.vehicle_tree.read().unwrap().find_bbox(extent)
+    }
+
+    /// Trips vehicles were seen on during a service date (`YYYYMMDD`)
+    pub async fn observed_trips(&self, date: &str) -> HashSet<String> {
+        self.observed_trips
+            .read()
+            .unwrap()
+            .get(date)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub async fn fetch_status(&self) -> FetchStatus {
+        self.fetch_status.read().unwrap().clone()
+    }
+
     pub async fn raw_data(&self) -> Vec<u8> {
         self.raw.read().unwrap().clone()
     }
 
+    pub async fn enriched_data(&self) -> Vec<u8> {
+        self.enriched.read().unwrap().clone()
+    }
+
+    pub fn get_trip_updates(&self) -> Arc<TripUpdates> {
+        self.trip_updates.read().unwrap().clone()
+    }
+
+    pub async fn alerts(&self) -> Vec<Alert> {
+        self.alerts.read().unwrap().clone()
+    }
+
+    pub fn get_predictions(&self) -> Arc<DashMap<String, TripPrediction>> {
+        self.predictions.clone()
+    }
+
+    pub fn get_eta_model(&self) -> PropagationModel {
+        self.eta_model
+    }
+
+    pub fn get_off_route_config(&self) -> OffRouteConfig {
+        self.off_route
+    }
+
     pub fn get_speeds(&self) -> Arc<DashMap<String, Arc<RwLock<BusSpeed>>>> {
         self.buses_speed.clone()
     }
+
+    pub fn get_vehicles_state(&self) -> Arc<DashMap<String, Arc<RwLock<VehicleState>>>> {
+        self.vehicles_state.clone()
+    }
 }
 
 fn compress_string(input: &str) -> Result<Vec<u8>, std::io::Error> {
@@ -230,3 +896,132 @@ fn compress_string(input: &str) -> Result<Vec<u8>, std::io::Error> {
     encoder.write_all(input.as_bytes())?;
     encoder.finish()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gtfs_structures::Stop;
+
+    fn stops(ids: &[&str]) -> Vec<StopTime> {
+        ids.iter()
+            .enumerate()
+            .map(|(i, id)| StopTime {
+                stop: Arc::new(Stop {
+                    id: id.to_string(),
+                    ..Default::default()
+                }),
+                stop_sequence: (i as u16 + 1) * 10,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn update(
+        stop_sequence: Option<u32>,
+        stop_id: Option<&str>,
+        delay: Option<i32>,
+        schedule_relationship: &str,
+    ) -> StopTimeUpdate {
+        StopTimeUpdate {
+            stop_sequence,
+            stop_id: stop_id.map(|e| e.to_string()),
+            arrival_delay: delay,
+            arrival_time: None,
+            departure_delay: None,
+            departure_time: None,
+            schedule_relationship: schedule_relationship.to_string(),
+        }
+    }
+
+    fn trip_update(stop_time_updates: Vec<StopTimeUpdate>) -> TripUpdate {
+        TripUpdate {
+            id: "1".to_string(),
+            trip_id: "trip".to_string(),
+            route_id: None,
+            start_date: None,
+            start_time: None,
+            vehicle_id: None,
+            timestamp: None,
+            delay: None,
+            schedule_relationship: "SCHEDULED".to_string(),
+            stop_time_updates,
+        }
+    }
+
+    #[test]
+    fn trip_updates_are_found_by_run() {
+        let run = |start_date: Option<&str>, delay: i32| TripUpdate {
+            start_date: start_date.map(str::to_string),
+            delay: Some(delay),
+            ..trip_update(vec![])
+        };
+        let trip_updates = [run(Some("20260616"), 60), run(Some("20260617"), 120)]
+            .into_iter()
+            .map(|e| (e.key(), e))
+            .collect::<TripUpdates>();
+
+        let found = |start_date| {
+            find_trip_update(&trip_updates, "trip", Some(start_date), None).and_then(|e| e.delay)
+        };
+        assert_eq!(found("20260616"), Some(60));
+        assert_eq!(found("20260617"), Some(120));
+        assert_eq!(found("20260618"), None);
+
+        //An undated update stands for any run
+        trip_updates.insert(run(None, 180).key(), run(None, 180));
+        assert_eq!(found("20260617"), Some(120));
+        assert_eq!(found("20260618"), Some(180));
+    }
+
+    #[test]
+    fn delay_propagates_by_stop_sequence() {
+        let stops = stops(&["a", "b", "c", "d"]);
+        let trip_update = trip_update(vec![
+            update(Some(10), None, Some(60), "SCHEDULED"),
+            update(Some(30), None, Some(120), "SCHEDULED"),
+        ]);
+
+        assert_eq!(trip_update.delay_at(&stops, 0), Some(60));
+        assert_eq!(trip_update.delay_at(&stops, 1), Some(60));
+        assert_eq!(trip_update.delay_at(&stops, 3), Some(120));
+    }
+
+    #[test]
+    fn delay_propagates_by_stop_id() {
+        let stops = stops(&["a", "b", "c", "d"]);
+        let trip_update = trip_update(vec![
+            update(None, Some("a"), Some(60), "SCHEDULED"),
+            update(None, Some("c"), Some(120), "SCHEDULED"),
+        ]);
+
+        assert_eq!(trip_update.delay_at(&stops, 1), Some(60));
+        assert_eq!(trip_update.delay_at(&stops, 2), Some(120));
+        assert_eq!(trip_update.delay_at(&stops, 3), Some(120));
+    }
+
+    #[test]
+    fn repeated_stop_id_is_placed_after_previous_update() {
+        let stops = stops(&["a", "b", "c", "a"]);
+        let trip_update = trip_update(vec![
+            update(None, Some("b"), Some(60), "SCHEDULED"),
+            update(None, Some("a"), Some(120), "SCHEDULED"),
+        ]);
+
+        assert_eq!(trip_update.delay_at(&stops, 0), None);
+        assert_eq!(trip_update.delay_at(&stops, 2), Some(60));
+        assert_eq!(trip_update.delay_at(&stops, 3), Some(120));
+    }
+
+    #[test]
+    fn skipped_and_no_data_stops() {
+        let stops = stops(&["a", "b", "c", "d"]);
+        let trip_update = trip_update(vec![
+            update(Some(10), None, Some(60), "SCHEDULED"),
+            update(Some(20), None, Some(600), "SKIPPED"),
+            update(Some(40), None, None, "NO_DATA"),
+        ]);
+
+        assert_eq!(trip_update.delay_at(&stops, 2), Some(60));
+        assert_eq!(trip_update.delay_at(&stops, 3), None);
+    }
+}
@@ -0,0 +1,241 @@
+use chrono::{DateTime, NaiveDate, NaiveTime, Offset, TimeZone};
+use chrono_tz::Tz;
+
+/// Local date at a unix time
+pub fn date(timezone: &Tz, time: i64) -> NaiveDate {
+    DateTime::from_timestamp(time, 0)
+        .unwrap_or_default()
+        .with_timezone(timezone)
+        .date_naive()
+}
+
+/// Unix time the stop times of a service date are measured from:
+/// noon minus 12h, which is not midnight on the days clocks change
+pub fn service_day_start(timezone: &Tz, date: NaiveDate) -> i64 {
+    let noon = date.and_time(NaiveTime::from_hms_opt(12, 0, 0).unwrap());
+    let noon = match timezone.from_local_datetime(&noon).earliest() {
+        Some(noon) => noon.timestamp(),
+        //Noon skipped by a zone change, only seen in old tz data
+        None => {
+            noon.and_utc().timestamp()
+                - timezone.offset_from_utc_date(&date).fix().local_minus_utc() as i64
+        }
+    };
+    noon - 43200
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use chrono::NaiveDateTime;
+    use chrono_tz::{America::New_York, Australia::Sydney, Europe::Brussels};
+    use gtfs_structures::{Calendar, StopTime, Trip};
+
+    use super::*;
+    use crate::{dataset::Dataset, utils};
+
+    fn utc(date: &str, time: &str) -> i64 {
+        NaiveDateTime::parse_from_str(&format!("{} {}", date, time), "%Y-%m-%d %H:%M:%S")
+            .unwrap()
+            .and_utc()
+            .timestamp()
+    }
+
+    fn date(date: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    fn service_day_start_on_brussels_dst_changes() {
+        //Noon minus 12h is 23:00 local the day before when clocks go forward
+        assert_eq!(
+            service_day_start(&Brussels, date("2026-03-29")),
+            utc("2026-03-28", "22:00:00")
+        );
+        //and 01:00 local when they go back
+        assert_eq!(
+            service_day_start(&Brussels, date("2026-10-25")),
+            utc("2026-10-24", "23:00:00")
+        );
+
+        //Local midnight on the other days
+        assert_eq!(
+            service_day_start(&Brussels, date("2026-03-28")),
+            utc("2026-03-27", "23:00:00")
+        );
+        assert_eq!(
+            service_day_start(&Brussels, date("2026-03-30")),
+            utc("2026-03-29", "22:00:00")
+        );
+        assert_eq!(
+            service_day_start(&Brussels, date("2026-10-24")),
+            utc("2026-10-23", "22:00:00")
+        );
+        assert_eq!(
+            service_day_start(&Brussels, date("2026-10-26")),
+            utc("2026-10-25", "23:00:00")
+        );
+    }
+
+    #[test]
+    fn service_day_start_on_other_dst_changes() {
+        //DST ends on 2026-04-05 in Sydney: 01:00 local
+        assert_eq!(
+            service_day_start(&Sydney, date("2026-04-05")),
+            utc("2026-04-04", "14:00:00")
+        );
+        //and starts on 2026-10-04: 23:00 local the day before
+        assert_eq!(
+            service_day_start(&Sydney, date("2026-10-04")),
+            utc("2026-10-03", "13:00:00")
+        );
+        assert_eq!(
+            service_day_start(&Sydney, date("2026-07-01")),
+            utc("2026-06-30", "14:00:00")
+        );
+
+        //and on 2026-03-08 and 2026-11-01 in New York
+        assert_eq!(
+            service_day_start(&New_York, date("2026-03-08")),
+            utc("2026-03-08", "04:00:00")
+        );
+        assert_eq!(
+            service_day_start(&New_York, date("2026-11-01")),
+            utc("2026-11-01", "05:00:00")
+        );
+    }
+
+    #[test]
+    fn local_date() {
+        assert_eq!(
+            super::date(&Brussels, utc("2026-03-28", "22:59:59")),
+            date("2026-03-28")
+        );
+        assert_eq!(
+            super::date(&Brussels, utc("2026-03-28", "23:00:00")),
+            date("2026-03-29")
+        );
+        assert_eq!(
+            super::date(&Sydney, utc("2026-01-15", "13:00:00")),
+            date("2026-01-16")
+        );
+    }
+
+    #[test]
+    fn stop_time_past_midnight() {
+        let time = utils::parse_time("25:30:00").unwrap();
+        assert_eq!(time, 91800);
+
+        //01:30 local the next day, whatever the offset
+        let at = service_day_start(&Brussels, date("2026-06-16")) + time as i64;
+        assert_eq!(at, utc("2026-06-16", "23:30:00"));
+        assert_eq!(super::date(&Brussels, at), date("2026-06-17"));
+
+        //Through the night clocks go back
+        let at = service_day_start(&Brussels, date("2026-10-24")) + time as i64;
+        assert_eq!(at, utc("2026-10-24", "23:30:00"));
+        assert_eq!(super::date(&Brussels, at), date("2026-10-25"));
+
+        //Measured from 01:00 local on the day they went back
+        let at = service_day_start(&Brussels, date("2026-10-25")) + time as i64;
+        assert_eq!(at, utc("2026-10-26", "00:30:00"));
+        assert_eq!(super::date(&Brussels, at), date("2026-10-26"));
+    }
+
+    fn dataset(days: [bool; 7]) -> Dataset {
+        let stop_time = |time: u32| StopTime {
+            arrival_time: Some(time),
+            departure_time: Some(time),
+            ..Default::default()
+        };
+        let trip = |id: &str, times: [u32; 2]| Trip {
+            id: id.to_string(),
+            service_id: "service".to_string(),
+            stop_times: times.into_iter().map(stop_time).collect(),
+            ..Default::default()
+        };
+
+        let mut dataset = Dataset {
+            timezone: Brussels,
+            ..Default::default()
+        };
+        dataset.gtfs.trips = HashMap::from([
+            ("night".to_string(), trip("night", [82800, 91800])),
+            ("day".to_string(), trip("day", [28800, 36000])),
+        ]);
+        dataset.gtfs.calendar = HashMap::from([(
+            "service".to_string(),
+            Calendar {
+                id: "service".to_string(),
+                monday: days[0],
+                tuesday: days[1],
+                wednesday: days[2],
+                thursday: days[3],
+                friday: days[4],
+                saturday: days[5],
+                sunday: days[6],
+                start_date: date("2026-01-01"),
+                end_date: date("2026-12-31"),
+            },
+        )]);
+        dataset
+    }
+
+    #[test]
+    fn service_date_of_trip_past_midnight() {
+        let dataset = dataset([true; 7]);
+        let night = &dataset.gtfs.trips["night"];
+        let day = &dataset.gtfs.trips["day"];
+
+        //01:15 local on Wednesday 2026-06-17
+        let now = utc("2026-06-16", "23:15:00");
+        assert_eq!(
+            utils::get_service_date(&dataset, night, None, now),
+            date("2026-06-16")
+        );
+        assert_eq!(
+            utils::get_service_date(&dataset, day, None, now),
+            date("2026-06-17")
+        );
+
+        //The start date of the feed wins
+        assert_eq!(
+            utils::get_service_date(&dataset, night, Some("20260617"), now),
+            date("2026-06-17")
+        );
+
+        //Before the trip starts, it runs today
+        let now = utc("2026-06-17", "20:00:00");
+        assert_eq!(
+            utils::get_service_date(&dataset, night, None, now),
+            date("2026-06-17")
+        );
+    }
+
+    #[test]
+    fn service_date_prefers_scheduled_day() {
+        //Not on Tuesdays: past midnight on Wednesday, the trip of Tuesday
+        //doesn't run, so the vehicle is early on the one of Wednesday
+        let dataset = dataset([true, false, true, true, true, true, true]);
+        let night = &dataset.gtfs.trips["night"];
+
+        let now = utc("2026-06-16", "23:15:00");
+        assert_eq!(
+            utils::get_service_date(&dataset, night, None, now),
+            date("2026-06-17")
+        );
+    }
+
+    #[test]
+    fn service_date_across_dst_change() {
+        //02:15 local on Sunday 2026-10-25, the night clocks go back
+        let dataset = dataset([true; 7]);
+        let night = &dataset.gtfs.trips["night"];
+        let now = utc("2026-10-25", "00:15:00");
+        assert_eq!(
+            utils::get_service_date(&dataset, night, None, now),
+            date("2026-10-24")
+        );
+    }
+}
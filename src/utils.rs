@@ -1,19 +1,32 @@
 const MAX_SPEEDS: usize = 100;
 const EXPIRE: usize = 10;
+const DELAY_TOLERANCE: f64 = 300.0;
+/// Above this speed between two fixes, one of them is a GPS jump (m/s)
+const MAX_FIX_SPEED: f64 = 40.0;
 
 use std::{
     collections::VecDeque,
     sync::{Arc, RwLock},
-    time::{SystemTime, UNIX_EPOCH},
 };
 
-use crate::{logger, store::BusSpeed};
-use chrono::Timelike;
-use gtfs_structures::{Gtfs, Shape, StopTime};
+use crate::{
+    calendar,
+    dataset::Dataset,
+    frequency::{self, EvaluationMode},
+    inference::{self, Position},
+    kalman::{Estimate, KalmanFilter},
+    logger, matching,
+    store::{self, BusSpeed, OffRouteConfig, VehicleState},
+    timezone,
+};
+use chrono::{DateTime, Days, NaiveDate, Utc};
+use gtfs_structures::{Gtfs, StopTime, Trip};
 
 use crate::{
-    gtfs_realtime::FeedEntity,
-    store::{Bus, Store},
+    gtfs_realtime::{trip_update::StopTimeEvent, FeedEntity, TranslatedString},
+    store::{
+        ActivePeriod, Alert, Bus, InformedEntity, StopTimeUpdate, Store, Translation, TripUpdate,
+    },
 };
 
 pub fn earth_distance(a: (f64, f64), b: (f64, f64)) -> f64 {
@@ -35,75 +48,118 @@ pub fn earth_distance(a: (f64, f64), b: (f64, f64)) -> f64 {
 }
 
 //Return bus with partial (or full) data otherwise None
-pub fn real_time_data(entity: &FeedEntity, store: &Store) -> Option<Bus> {
+pub fn real_time_data(entity: &FeedEntity, store: &Store, now: DateTime<Utc>) -> Option<Bus> {
     let vehicle = entity.vehicle.0.as_ref()?;
     let position = &vehicle.position;
     let latitude = position.latitude?;
     let longitude = position.longitude?;
     let id = entity.id.clone()?;
 
-    let timestamp: u64 = vehicle.timestamp.or_else(|| {
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .ok()
-            .map(|e| e.as_secs())
-    })?;
+    let timestamp: u64 = vehicle
+        .timestamp
+        .unwrap_or_else(|| now.timestamp().max(0) as u64);
 
     let mut bus = Bus::default();
     bus.set_timestamp(timestamp);
     bus.set_id(&id);
     bus.set_position(latitude, longitude);
 
-    let speed = match position.speed {
-        Some(e) => {
-            bus.set_speed(e);
-            e
-        }
-        None => 0.0,
-    };
-
-    let binding = store.get_gtfs();
-    let gtfs = match binding.read() {
-        Ok(e) => e,
-        Err(_) => return Some(bus),
+    let dataset = store.dataset();
+    let gtfs = &dataset.gtfs;
+
+    let vehicle_state = get_vehicle_state(store, &id);
+    let (positions, previous_trip) = {
+        let mut vehicle_state = vehicle_state.write().unwrap();
+        vehicle_state.expire = EXPIRE;
+        insert_position(&mut vehicle_state, (timestamp, latitude, longitude));
+        (
+            vehicle_state.positions.clone(),
+            vehicle_state.trip_id.clone(),
+        )
     };
 
-    let line_id = match &vehicle.trip.route_id {
+    //Vehicles not reporting their speed get the one between their last fixes
+    let speed = match position.speed.or_else(|| get_fix_speed(&positions)) {
         Some(e) => {
-            bus.set_line_id(e);
+            bus.set_speed(e);
             e
         }
-        None => return Some(bus),
+        None => 0.0,
     };
 
-    let line = get_line(&gtfs, line_id.to_string());
-    match line {
-        Some((line, agency)) => {
-            bus.set_line(&line);
-            bus.set_agency_id(&agency);
-        }
-        _ => {
-            logger::warn("UTILS", &format!("No line (or agency) found: {}", line_id));
+    let route_id = vehicle.trip.route_id.as_deref();
+    if let Some(line_id) = route_id {
+        bus.set_line_id(line_id);
+        if !set_line(&mut bus, gtfs, line_id) {
             return Some(bus);
         }
-    };
+    }
 
-    let trip_id = match &vehicle.trip.trip_id {
-        Some(e) => {
-            bus.set_trip_id(&e);
-            e
+    //Trips missing from the feed or unknown to the GTFS are inferred
+    let trip = vehicle
+        .trip
+        .trip_id
+        .as_ref()
+        .and_then(|trip_id| get_trip(gtfs, trip_id.to_string()));
+    let (trip, service_date) = match trip {
+        Some(trip) => {
+            let service_date = get_service_date(
+                &dataset,
+                trip,
+                vehicle.trip.start_date.as_deref(),
+                now.timestamp(),
+            );
+            (trip, service_date)
         }
-        None => return Some(bus),
-    };
-
-    let trip = get_trip(&gtfs, trip_id.to_string());
-    let trip = match trip {
-        Some(e) => e,
         None => {
-            logger::warn("UTILS", &format!("No trip found: {}", trip_id));
-            return Some(bus);
+            if let Some(trip_id) = &vehicle.trip.trip_id {
+                bus.set_trip_id(trip_id);
+                logger::warn("UTILS", &format!("No trip found: {}", trip_id));
+            }
+
+            let inference = inference::infer_trip(
+                &dataset,
+                route_id,
+                &positions,
+                &previous_trip,
+                timestamp as i64,
+            );
+            let inference = match inference {
+                Some(e) => e,
+                None => return Some(bus),
+            };
+            if inference.trip.id != previous_trip {
+                logger::info(
+                    "INFERENCE",
+                    &format!(
+                        "Vehicle {} assigned to trip {} (was {}, confidence {:.2})",
+                        id,
+                        inference.trip.id,
+                        match previous_trip.is_empty() {
+                            true => "none",
+                            false => &previous_trip,
+                        },
+                        inference.confidence
+                    ),
+                );
+            }
+
+            if route_id.is_none() {
+                bus.set_line_id(&inference.trip.route_id);
+                if !set_line(&mut bus, gtfs, &inference.trip.route_id) {
+                    return Some(bus);
+                }
+            }
+            bus.set_trip_confidence(inference.confidence);
+            (inference.trip, inference.service_date)
         }
     };
+    let trip_id = &trip.id;
+    bus.set_trip_id(trip_id);
+
+    bus.set_start_date(&service_date.format("%Y%m%d").to_string());
+    let unscheduled = !calendar::is_active(gtfs, &trip.service_id, service_date);
+    bus.set_unscheduled(unscheduled);
 
     let shape_id = trip.shape_id.clone();
     let shape_id = match shape_id {
@@ -111,7 +167,7 @@ pub fn real_time_data(entity: &FeedEntity, store: &Store) -> Option<Bus> {
         None => return Some(bus),
     };
 
-    let shape = get_shape(&gtfs, &shape_id);
+    let shape = get_shape(gtfs, &shape_id);
     let shape = match shape {
         Some(e) => e,
         None => {
@@ -129,180 +185,462 @@ pub fn real_time_data(entity: &FeedEntity, store: &Store) -> Option<Bus> {
         return Some(bus);
     }
 
-    let (reverse_stop, reverse_shape) = make_cache(&trip.stop_times, &shape);
+    let index = &dataset.index;
 
-    if reverse_stop.is_empty() || reverse_shape.is_empty() {
-        return Some(bus);
+    let (projection, distances) = match (
+        index.get_trip(trip_id),
+        index.get_shape_distances(&shape_id),
+    ) {
+        (Some(projection), Some(distances)) => (projection, distances),
+        _ => return Some(bus),
+    };
+
+    let mut vehicle_state = vehicle_state.write().unwrap();
+    if vehicle_state.trip_id != *trip_id {
+        vehicle_state.trip_id = trip_id.to_string();
+        vehicle_state.shape_dist_traveled = None;
+        vehicle_state.off_route_fixes = 0;
+        vehicle_state.on_route_fixes = 0;
+        vehicle_state.is_out = false;
+        vehicle_state.out_since = None;
+        vehicle_state.rejoined_at = None;
+        vehicle_state.filter = None;
+
+        if unscheduled {
+            logger::warn(
+                "UTILS",
+                &format!(
+                    "Vehicle {} runs trip {} which is not scheduled on {}",
+                    id, trip_id, service_date
+                ),
+            );
+        }
     }
 
-    let (shape_idx, nearest_shape) = match find_nearest_shape(&shape, latitude, longitude) {
+    let current_time = get_current_time(
+        timezone::service_day_start(&dataset.timezone, service_date),
+        now,
+    );
+    let start_time = vehicle.trip.start_time.as_deref().and_then(parse_time);
+
+    //The first match is seeded where the schedule puts the vehicle, which
+    //tells the two legs of an out-and-back route apart
+    let hint = match (trip.frequencies.is_empty(), start_time) {
+        (true, _) => Some(0),
+        (false, Some(start_time)) => frequency::offset(trip, start_time),
+        (false, None) => None,
+    }
+    .and_then(|offset| {
+        get_scheduled_distance(
+            &trip.stop_times,
+            &projection.stop_distances,
+            current_time as f64 - offset as f64,
+        )
+    });
+
+    let matched = matching::match_vehicle(
+        shape,
+        &distances,
+        latitude as f64,
+        longitude as f64,
+        vehicle_state.shape_dist_traveled,
+        timestamp.saturating_sub(vehicle_state.progress_at),
+        hint,
+    );
+    let matched = match matched {
         Some(e) => e,
         None => return Some(bus),
     };
 
-    let next_stop = reverse_shape[shape_idx];
-    bus.set_next_stop(next_stop);
-
-    let current_time = get_current_time(&trip.stop_times);
+    let off_route = store.get_off_route_config();
+    update_off_route(
+        &mut vehicle_state,
+        &off_route,
+        &id,
+        timestamp,
+        matched.distance,
+    );
+    bus.set_distance_to_shape(matched.distance);
+    bus.set_is_out(vehicle_state.is_out);
+    bus.set_out_since(vehicle_state.out_since);
+    bus.set_rejoined_at(vehicle_state.rejoined_at);
+
+    //A match far from the shape is meaningless, keep the last progress
+    let shape_dist_traveled = match matched.distance <= off_route.distance {
+        true => {
+            let estimate = filter_progress(
+                &mut vehicle_state,
+                timestamp,
+                matched.shape_dist_traveled,
+                position.speed.map(|e| e as f64),
+            );
+            bus.set_estimated_speed(estimate.speed);
+            bus.set_acceleration(estimate.acceleration);
+            bus.set_position_rejected(estimate.rejected);
+            vehicle_state.progress_at = timestamp;
+
+            //Matched progress never goes back, nor does the filtered one
+            match vehicle_state.shape_dist_traveled {
+                Some(previous) => estimate.shape_dist_traveled.max(previous),
+                None => estimate.shape_dist_traveled,
+            }
+        }
+        false => vehicle_state
+            .shape_dist_traveled
+            .unwrap_or(matched.shape_dist_traveled),
+    };
+    vehicle_state.shape_dist_traveled = Some(shape_dist_traveled);
+    bus.set_shape_dist_traveled(shape_dist_traveled);
 
-    let theorical_stop = find_theorical_stop(&trip.stop_times, current_time);
-    bus.set_theorical_stop(theorical_stop);
+    let next_stop = find_next_stop(&projection.stop_distances, shape_dist_traveled);
+    bus.set_next_stop(next_stop);
 
-    let remaining_distance = calculate_remaining_distance(
-        &shape,
-        shape_idx,
-        nearest_shape,
-        latitude,
-        longitude,
-        reverse_stop[next_stop],
-    );
+    let remaining_distance =
+        calculate_remaining_distance(&projection.stop_distances, shape_dist_traveled, next_stop);
     bus.set_remaining_distance(remaining_distance);
 
     let (next_stops_time, total_next_distance) =
-        match calculate_next_stop_data(&trip.stop_times, &shape, reverse_stop, next_stop) {
-            Some(e) => e,
-            None => return None,
-        };
+        calculate_next_stop_data(&trip.stop_times, &projection.stop_distances, next_stop)?;
 
-    let delay = get_delay(
+    let mut delay = get_delay(
         current_time,
         next_stops_time,
         remaining_distance,
         total_next_distance,
     );
+
+    //A frequency-based trip runs its stop times shifted to each departure
+    let offset = match frequency::instance(trip, start_time, delay) {
+        Some(instance) => {
+            bus.set_mode(instance.mode);
+            bus.set_start_time(&format_time(instance.start_time));
+            if instance.mode == EvaluationMode::Headway {
+                bus.set_scheduled_headway(instance.headway);
+            }
+            delay -= instance.offset as f64;
+            instance.offset
+        }
+        None => 0,
+    };
+
+    let theorical_stop = find_theorical_stop(
+        &trip.stop_times,
+        (current_time as i64 - offset).max(0) as u32,
+    );
+    bus.set_theorical_stop(theorical_stop);
+
+    //Untimed departures have no delay, nor trip updates telling their
+    //vehicles apart: they are evaluated on their headway
+    if bus.mode == EvaluationMode::Headway {
+        return Some(bus);
+    }
     bus.set_delay(delay);
 
+    if let Some(reported_delay) = get_reported_delay(
+        store,
+        trip,
+        bus.start_date.as_deref(),
+        vehicle.trip.start_time.as_deref(),
+        next_stop,
+    ) {
+        bus.set_reported_delay(reported_delay);
+        if (reported_delay - delay).abs() > DELAY_TOLERANCE {
+            logger::warn(
+                "UTILS",
+                &format!(
+                    "Delay mismatch on trip {}: computed {:.0}s, reported {:.0}s",
+                    trip_id, delay, reported_delay
+                ),
+            );
+        }
+    }
+
     Some(bus)
 }
 
-fn get_line(gtfs: &Gtfs, line_id: String) -> Option<(String, String)> {
-    let route = gtfs.routes.get(&line_id)?;
-    match (route.short_name.clone(), route.agency_id.clone()) {
-        (Some(short_name), Some(agency_id)) => Some((short_name, agency_id)),
-        _ => None,
-    }
-}
+//Return trip update if the entity carries one
+pub fn trip_update_data(entity: &FeedEntity) -> Option<TripUpdate> {
+    let trip_update = entity.trip_update.0.as_ref()?;
+    let trip_id = trip_update.trip.trip_id.clone()?;
+    let id = entity.id.clone().unwrap_or(trip_id.clone());
 
-fn get_trip<'a>(gtfs: &'a Gtfs, trip_id: String) -> Option<&'a gtfs_structures::Trip> {
-    gtfs.trips.get(&trip_id)
+    let stop_time_updates = trip_update
+        .stop_time_update
+        .iter()
+        .map(|update| {
+            let (arrival_delay, arrival_time) = stop_time_event(update.arrival.0.as_deref());
+            let (departure_delay, departure_time) = stop_time_event(update.departure.0.as_deref());
+
+            StopTimeUpdate {
+                stop_sequence: update.stop_sequence,
+                stop_id: update.stop_id.clone(),
+                arrival_delay,
+                arrival_time,
+                departure_delay,
+                departure_time,
+                schedule_relationship: format!(
+                    "{:?}",
+                    update
+                        .schedule_relationship
+                        .unwrap_or_default()
+                        .enum_value_or_default()
+                ),
+            }
+        })
+        .collect::<Vec<StopTimeUpdate>>();
+
+    Some(TripUpdate {
+        id,
+        trip_id,
+        route_id: trip_update.trip.route_id.clone(),
+        start_date: trip_update.trip.start_date.clone(),
+        start_time: trip_update.trip.start_time.clone(),
+        vehicle_id: trip_update.vehicle.id.clone(),
+        timestamp: trip_update.timestamp,
+        delay: trip_update.delay,
+        schedule_relationship: format!(
+            "{:?}",
+            trip_update
+                .trip
+                .schedule_relationship
+                .unwrap_or_default()
+                .enum_value_or_default()
+        ),
+        stop_time_updates,
+    })
 }
 
-fn get_shape<'a>(gtfs: &'a Gtfs, shape_id: &String) -> Option<&'a Vec<gtfs_structures::Shape>> {
-    gtfs.shapes.get(shape_id)
-}
+//Return alert if the entity carries one
+pub fn alert_data(entity: &FeedEntity) -> Option<Alert> {
+    let alert = entity.alert.0.as_ref()?;
+    let id = entity.id.clone()?;
 
-fn make_cache(stops: &Vec<StopTime>, shape: &Vec<Shape>) -> (Vec<usize>, Vec<usize>) {
-    let mut reverse_stop: Vec<usize> = Vec::with_capacity(stops.len());
-    let mut reverse_shape: Vec<usize> = Vec::with_capacity(shape.len());
+    let active_periods = alert
+        .active_period
+        .iter()
+        .map(|period| ActivePeriod {
+            start: period.start,
+            end: period.end,
+        })
+        .collect::<Vec<ActivePeriod>>();
+
+    let informed_entities = alert
+        .informed_entity
+        .iter()
+        .map(|entity| InformedEntity {
+            agency_id: entity.agency_id.clone(),
+            route_id: entity.route_id.clone(),
+            trip_id: entity.trip.trip_id.clone(),
+            stop_id: entity.stop_id.clone(),
+        })
+        .collect::<Vec<InformedEntity>>();
+
+    Some(Alert {
+        id,
+        cause: format!(
+            "{:?}",
+            alert.cause.unwrap_or_default().enum_value_or_default()
+        ),
+        effect: format!(
+            "{:?}",
+            alert.effect.unwrap_or_default().enum_value_or_default()
+        ),
+        severity: format!(
+            "{:?}",
+            alert
+                .severity_level
+                .unwrap_or_default()
+                .enum_value_or_default()
+        ),
+        header: translations(alert.header_text.0.as_deref()),
+        description: translations(alert.description_text.0.as_deref()),
+        url: translations(alert.url.0.as_deref()),
+        active_periods,
+        informed_entities,
+    })
+}
 
-    let mut last_index: usize = 0;
-    for stop in stops {
-        let stop_lat = match stop.stop.latitude {
-            Some(e) => e,
-            None => continue,
-        };
+fn stop_time_event(event: Option<&StopTimeEvent>) -> (Option<i32>, Option<i64>) {
+    match event {
+        Some(event) => (event.delay, event.time),
+        None => (None, None),
+    }
+}
 
-        let stop_long = match stop.stop.longitude {
-            Some(e) => e,
-            None => continue,
-        };
+fn translations(text: Option<&TranslatedString>) -> Vec<Translation> {
+    let text = match text {
+        Some(e) => e,
+        None => return vec![],
+    };
 
-        let mut smallest = f64::INFINITY;
-        for (i, shape) in shape.iter().enumerate() {
-            let shape_lat = shape.latitude;
-            let shape_long = shape.longitude;
+    text.translation
+        .iter()
+        .flat_map(|translation| {
+            Some(Translation {
+                language: translation.language.clone(),
+                text: translation.text.clone()?,
+            })
+        })
+        .collect()
+}
 
-            let dist = (shape_lat - stop_lat).powi(2) + (shape_long - stop_long).powi(2);
+fn get_reported_delay(
+    store: &Store,
+    trip: &Trip,
+    start_date: Option<&str>,
+    start_time: Option<&str>,
+    stop: usize,
+) -> Option<f64> {
+    let trip_updates = store.get_trip_updates();
+    let trip_update = store::find_trip_update(&trip_updates, &trip.id, start_date, start_time)?;
+    let delay = trip_update.delay_at(&trip.stop_times, stop)?;
+    Some(delay as f64)
+}
 
-            if dist < smallest {
-                smallest = dist;
-                last_index = i;
-            }
+/// Line and agency of the route, `false` if the route lacks them
+fn set_line(bus: &mut Bus, gtfs: &Gtfs, line_id: &str) -> bool {
+    match get_line(gtfs, line_id.to_string()) {
+        Some((line, agency)) => {
+            bus.set_line(&line);
+            bus.set_agency_id(&agency);
+            true
+        }
+        None => {
+            logger::warn("UTILS", &format!("No line (or agency) found: {}", line_id));
+            false
         }
-        reverse_stop.push(last_index);
     }
+}
 
-    if reverse_stop.len() != stops.len() || reverse_stop.is_empty() {
-        println!("Error reverse stop");
-        return (vec![], vec![]);
+fn get_line(gtfs: &Gtfs, line_id: String) -> Option<(String, String)> {
+    let route = gtfs.routes.get(&line_id)?;
+    match (route.short_name.clone(), route.agency_id.clone()) {
+        (Some(short_name), Some(agency_id)) => Some((short_name, agency_id)),
+        _ => None,
     }
+}
 
-    last_index = 0;
-    for (idx, shape) in shape.iter().enumerate() {
-        let mut smallest = f64::INFINITY;
-        let shape_lat = shape.latitude;
-        let shape_long = shape.longitude;
-
-        for (i, stop) in stops.iter().enumerate() {
-            let stop_lat = match stop.stop.latitude {
-                Some(e) => e,
-                None => continue,
-            };
-
-            let stop_long = match stop.stop.longitude {
-                Some(e) => e,
-                None => continue,
-            };
-
-            let dist = (shape_lat - stop_lat).powi(2) + (shape_long - stop_long).powi(2);
-
-            if dist < smallest {
-                smallest = dist;
-                last_index = i;
-            }
-        }
+fn get_trip(gtfs: &Gtfs, trip_id: String) -> Option<&gtfs_structures::Trip> {
+    gtfs.trips.get(&trip_id)
+}
 
-        if last_index < stops.len() - 1 && reverse_stop[last_index] <= idx {
-            last_index += 1;
-        }
+fn get_shape<'a>(gtfs: &'a Gtfs, shape_id: &String) -> Option<&'a Vec<gtfs_structures::Shape>> {
+    gtfs.shapes.get(shape_id)
+}
 
-        reverse_shape.push(last_index);
+//Only new fixes count, so a vehicle leaves or rejoins the route after
+//several consecutive positions on the same side of the threshold
+fn update_off_route(
+    vehicle_state: &mut VehicleState,
+    config: &OffRouteConfig,
+    id: &str,
+    timestamp: u64,
+    distance: f64,
+) {
+    if vehicle_state.timestamp == timestamp {
+        return;
     }
-
-    if reverse_shape.len() != shape.len() || reverse_shape.is_empty() {
-        println!("Error reverse shape");
-        return (vec![], vec![]);
+    vehicle_state.timestamp = timestamp;
+
+    if distance > config.distance {
+        vehicle_state.off_route_fixes += 1;
+        vehicle_state.on_route_fixes = 0;
+    } else {
+        vehicle_state.on_route_fixes += 1;
+        vehicle_state.off_route_fixes = 0;
     }
 
-    (reverse_stop, reverse_shape)
+    if !vehicle_state.is_out && vehicle_state.off_route_fixes >= config.fixes {
+        vehicle_state.is_out = true;
+        vehicle_state.out_since = Some(timestamp);
+        logger::info(
+            "UTILS",
+            &format!(
+                "Vehicle {} left trip {} ({:.0}m from shape)",
+                id, vehicle_state.trip_id, distance
+            ),
+        );
+    } else if vehicle_state.is_out && vehicle_state.on_route_fixes >= config.fixes {
+        vehicle_state.is_out = false;
+        vehicle_state.rejoined_at = Some(timestamp);
+        let duration = timestamp.saturating_sub(vehicle_state.out_since.unwrap_or(timestamp));
+        vehicle_state.out_since = None;
+        logger::info(
+            "UTILS",
+            &format!(
+                "Vehicle {} rejoined trip {} after {}s",
+                id, vehicle_state.trip_id, duration
+            ),
+        );
+    }
 }
 
-fn find_nearest_shape(
-    shape: &Vec<Shape>,
-    latitude: f32,
-    longitude: f32,
-) -> Option<(usize, &Shape)> {
-    shape.iter().enumerate().min_by(|(_, a), (_, b)| {
-        let a_dist =
-            (a.latitude - latitude as f64).powi(2) + (a.longitude - longitude as f64).powi(2);
-        let b_dist =
-            (b.latitude - latitude as f64).powi(2) + (b.longitude - longitude as f64).powi(2);
-        a_dist.partial_cmp(&b_dist).unwrap()
-    })
+fn find_next_stop(stop_distances: &[f64], shape_dist_traveled: f64) -> usize {
+    let next_stop = stop_distances.partition_point(|&e| e < shape_dist_traveled);
+    next_stop.min(stop_distances.len() - 1)
 }
 
-fn get_current_time(stops: &Vec<StopTime>) -> u32 {
-    let current_time = chrono::Local::now().time();
-    let mut current_time = current_time.num_seconds_from_midnight();
+/// Service date of a trip running at `now`: the start date given by the
+/// feed, otherwise today or, for a trip still running past midnight, the
+/// day before, preferring a date the trip is scheduled on
+pub fn get_service_date(
+    dataset: &Dataset,
+    trip: &Trip,
+    start_date: Option<&str>,
+    now: i64,
+) -> NaiveDate {
+    if let Some(date) = start_date.and_then(parse_date) {
+        return date;
+    }
 
-    let (first_stop, last_stop) = match (stops.first(), stops.last()) {
-        (Some(first), Some(last)) => (first, last),
-        _ => return current_time,
+    let today = timezone::date(&dataset.timezone, now);
+    let (first_departure, last_arrival) = match frequency::running_span(trip) {
+        Some(e) => e,
+        None => return today,
     };
 
-    let (first_arrival, last_arrival) = match (first_stop.arrival_time, last_stop.arrival_time) {
-        (Some(first), Some(last)) => (first, last),
-        _ => return current_time,
+    //How far now is from the trip running on a date
+    let distance = |date: &NaiveDate| {
+        let time = now - timezone::service_day_start(&dataset.timezone, *date);
+        (first_departure - time).max(time - last_arrival).max(0)
     };
 
-    if current_time < first_arrival && last_arrival > 86400 {
-        current_time += 86400;
+    let dates = [today, today - Days::new(1)];
+    dates
+        .into_iter()
+        .filter(|date| calendar::is_active(&dataset.gtfs, &trip.service_id, *date))
+        .min_by_key(distance)
+        .or_else(|| dates.into_iter().min_by_key(distance))
+        .unwrap_or(today)
+}
+
+/// Date of GTFS and GTFS-RT (`YYYYMMDD`)
+pub fn parse_date(date: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(date, "%Y%m%d").ok()
+}
+
+/// Time of GTFS and GTFS-RT (`HH:MM:SS`, past 24:00:00 after midnight) in
+/// seconds since the start of the service day
+pub fn parse_time(time: &str) -> Option<u32> {
+    let mut parts = time.split(':').map(|e| e.parse::<u32>().ok());
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(Some(h)), Some(Some(m)), Some(Some(s)), None) if m < 60 && s < 60 => {
+            Some(h * 3600 + m * 60 + s)
+        }
+        _ => None,
     }
+}
+
+pub fn format_time(time: u32) -> String {
+    format!("{:02}:{:02}:{:02}", time / 3600, time / 60 % 60, time % 60)
+}
 
-    current_time
+/// Schedule time at `now`, in seconds since the start of the service day
+fn get_current_time(service_day_start: i64, now: DateTime<Utc>) -> u32 {
+    (now.timestamp() - service_day_start).clamp(0, u32::MAX as i64) as u32
 }
 
-fn find_theorical_stop(stops: &Vec<StopTime>, current_time: u32) -> usize {
+fn find_theorical_stop(stops: &[StopTime], current_time: u32) -> usize {
     let theorical_stop = stops
         .iter()
         .enumerate()
@@ -317,37 +655,74 @@ fn find_theorical_stop(stops: &Vec<StopTime>, current_time: u32) -> usize {
     }
 }
 
-fn calculate_remaining_distance(
-    shape: &Vec<Shape>,
-    shape_idx: usize,
-    nearest_shape: &Shape,
-    latitude: f32,
-    longitude: f32,
-    next_stop: usize,
-) -> f64 {
-    let mut remaining_distance = earth_distance(
-        (latitude as f64, longitude as f64),
-        (nearest_shape.latitude, nearest_shape.longitude),
-    );
+/// Stop time interpolated at a distance along the shape
+pub fn get_scheduled_time(
+    stops: &[StopTime],
+    stop_distances: &[f64],
+    distance: f64,
+) -> Option<f64> {
+    if stop_distances.len() < 2 || stop_distances.len() != stops.len() {
+        return None;
+    }
 
-    for i in shape_idx..next_stop - 1 {
-        if i == shape.len() - 1 {
-            break;
-        }
+    let i = stop_distances
+        .partition_point(|e| *e < distance)
+        .clamp(1, stop_distances.len() - 1);
+    let (from, to) = (&stops[i - 1], &stops[i]);
+    let from_time = from.departure_time.or(from.arrival_time)? as f64;
+    let to_time = to.arrival_time.or(to.departure_time)? as f64;
+
+    let length = stop_distances[i] - stop_distances[i - 1];
+    let ratio = match length > 0.0 {
+        true => ((distance - stop_distances[i - 1]) / length).clamp(0.0, 1.0),
+        false => 1.0,
+    };
 
-        remaining_distance += earth_distance(
-            (shape[i].latitude, shape[i].longitude),
-            (shape[i + 1].latitude, shape[i + 1].longitude),
-        );
+    Some(from_time + (to_time - from_time) * ratio)
+}
+
+/// Distance along the shape interpolated at a stop time, the inverse of
+/// `get_scheduled_time`
+fn get_scheduled_distance(stops: &[StopTime], stop_distances: &[f64], time: f64) -> Option<f64> {
+    if stop_distances.len() != stops.len() {
+        return None;
     }
 
-    remaining_distance
+    //Stops without a time are skipped, only timepoints may have one
+    let points = stops
+        .iter()
+        .zip(stop_distances)
+        .filter_map(|(stop, distance)| {
+            let time = stop.arrival_time.or(stop.departure_time)?;
+            Some((time as f64, *distance))
+        })
+        .collect::<Vec<(f64, f64)>>();
+
+    let i = points.partition_point(|(e, _)| *e < time);
+    let ((from_time, from), (to_time, to)) = match i {
+        0 => return points.first().map(|(_, e)| *e),
+        i if i == points.len() => return points.last().map(|(_, e)| *e),
+        i => (points[i - 1], points[i]),
+    };
+
+    let ratio = match to_time > from_time {
+        true => (time - from_time) / (to_time - from_time),
+        false => 1.0,
+    };
+    Some(from + (to - from) * ratio)
+}
+
+fn calculate_remaining_distance(
+    stop_distances: &[f64],
+    shape_dist_traveled: f64,
+    next_stop: usize,
+) -> f64 {
+    (stop_distances[next_stop] - shape_dist_traveled).max(0.0)
 }
 
 fn calculate_next_stop_data(
-    stops: &Vec<StopTime>,
-    shape: &Vec<Shape>,
-    reverse_stop: Vec<usize>,
+    stops: &[StopTime],
+    stop_distances: &[f64],
     next_stop: usize,
 ) -> Option<([u32; 2], f64)> {
     let (first_stop, last_stop) = match next_stop {
@@ -356,21 +731,11 @@ fn calculate_next_stop_data(
     };
 
     let next_stops_time = [
-        stops[first_stop].arrival_time?,
-        stops[last_stop].arrival_time?,
+        stops.get(first_stop)?.arrival_time?,
+        stops.get(last_stop)?.arrival_time?,
     ];
 
-    let mut total_next_distance = 0.0;
-    for i in reverse_stop[first_stop]..reverse_stop[last_stop] {
-        if i == shape.len() - 1 {
-            break;
-        }
-
-        total_next_distance += earth_distance(
-            (shape[i].latitude, shape[i].longitude),
-            (shape[i + 1].latitude, shape[i + 1].longitude),
-        );
-    }
+    let total_next_distance = stop_distances[last_stop] - stop_distances[first_stop];
 
     Some((next_stops_time, total_next_distance))
 }
@@ -386,9 +751,7 @@ fn get_delay(
 
     let time_to_next_stop = (time_to_next_stop / total_next_distance) * remaining_distance;
 
-    let delay = (current_time as f64) + time_to_next_stop - (next_stops_time[1] as f64);
-
-    delay
+    (current_time as f64) + time_to_next_stop - (next_stops_time[1] as f64)
 }
 
 fn insert_speeds(bus_speeds: &mut BusSpeed, speed: f32) -> (f32, usize) {
@@ -403,6 +766,53 @@ fn insert_speeds(bus_speeds: &mut BusSpeed, speed: f32) -> (f32, usize) {
     (average, bus_speeds.speeds.len())
 }
 
+/// Smoothed progress along the shape, GPS jumps rejected
+fn filter_progress(
+    vehicle_state: &mut VehicleState,
+    timestamp: u64,
+    shape_dist_traveled: f64,
+    speed: Option<f64>,
+) -> Estimate {
+    match &mut vehicle_state.filter {
+        Some(filter) => filter.update(timestamp, shape_dist_traveled, speed),
+        None => {
+            let filter = KalmanFilter::new(timestamp, shape_dist_traveled, speed);
+            let estimate = filter.estimate();
+            vehicle_state.filter = Some(filter);
+            estimate
+        }
+    }
+}
+
+/// Speed between the last two fixes (m/s), `None` if it is not a plausible
+/// one
+fn get_fix_speed(positions: &VecDeque<Position>) -> Option<f32> {
+    let (from, to) = match positions.len() {
+        0 | 1 => return None,
+        len => (positions[len - 2], positions[len - 1]),
+    };
+
+    let duration = to.0.checked_sub(from.0).filter(|e| *e > 0)? as f64;
+    let distance = earth_distance((from.1 as f64, from.2 as f64), (to.1 as f64, to.2 as f64));
+    let speed = distance / duration;
+    (speed <= MAX_FIX_SPEED).then_some(speed as f32)
+}
+
+fn insert_position(vehicle_state: &mut VehicleState, position: Position) {
+    if vehicle_state
+        .positions
+        .back()
+        .is_some_and(|(timestamp, _, _)| *timestamp == position.0)
+    {
+        return;
+    }
+
+    vehicle_state.positions.push_back(position);
+    if vehicle_state.positions.len() > inference::MAX_POSITIONS {
+        vehicle_state.positions.pop_front();
+    }
+}
+
 fn get_bus_speed(store: &Store, id: &str) -> Arc<RwLock<BusSpeed>> {
     let avg_spd = store.get_speeds();
     let value = avg_spd.get(id);
@@ -419,3 +829,63 @@ fn get_bus_speed(store: &Store, id: &str) -> Arc<RwLock<BusSpeed>> {
         }
     }
 }
+
+fn get_vehicle_state(store: &Store, id: &str) -> Arc<RwLock<VehicleState>> {
+    let vehicles_state = store.get_vehicles_state();
+    let value = vehicles_state.get(id);
+    match value {
+        Some(vehicle_state) => vehicle_state.value().clone(),
+        None => {
+            let vehicle_state = Arc::new(RwLock::new(VehicleState {
+                expire: EXPIRE,
+                trip_id: String::new(),
+                timestamp: 0,
+                shape_dist_traveled: None,
+                progress_at: 0,
+                off_route_fixes: 0,
+                on_route_fixes: 0,
+                is_out: false,
+                out_since: None,
+                rejoined_at: None,
+                positions: VecDeque::new(),
+                filter: None,
+            }));
+            vehicles_state.insert(id.to_string(), vehicle_state.clone());
+            vehicle_state
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fix_speed_from_last_positions() {
+        let mut positions = VecDeque::new();
+        assert_eq!(get_fix_speed(&positions), None);
+
+        positions.push_back((1000, 50.0, 4.0));
+        assert_eq!(get_fix_speed(&positions), None);
+
+        //About 111m north in 10s
+        positions.push_back((1010, 50.001, 4.0));
+        let speed = get_fix_speed(&positions).unwrap();
+        assert!((speed - 11.1).abs() < 0.1, "{}", speed);
+
+        //Only the last two positions count
+        positions.push_back((1030, 50.001, 4.0));
+        assert_eq!(get_fix_speed(&positions), Some(0.0));
+    }
+
+    #[test]
+    fn no_fix_speed_from_implausible_positions() {
+        //Same timestamp
+        let positions = VecDeque::from([(1000, 50.0, 4.0), (1000, 50.001, 4.0)]);
+        assert_eq!(get_fix_speed(&positions), None);
+
+        //About 1.1km in 10s
+        let positions = VecDeque::from([(1000, 50.0, 4.0), (1010, 50.01, 4.0)]);
+        assert_eq!(get_fix_speed(&positions), None);
+    }
+}
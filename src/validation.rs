@@ -0,0 +1,133 @@
+use std::collections::BTreeMap;
+
+use gtfs_structures::Gtfs;
+use serde::Serialize;
+
+use crate::{index::GtfsIndex, logger};
+
+/// Examples kept per category
+const MAX_EXAMPLES: usize = 10;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Issue {
+    pub count: usize,
+    pub examples: Vec<String>,
+}
+
+/// Problems of a static GTFS that make vehicles lose their line, delay or
+/// distances, by category
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ValidationReport {
+    pub generated_at: i64,
+    pub issues: BTreeMap<&'static str, Issue>,
+}
+
+impl ValidationReport {
+    pub fn build(gtfs: &Gtfs, index: &GtfsIndex) -> Self {
+        let mut report = Self {
+            generated_at: chrono::Utc::now().timestamp(),
+            issues: BTreeMap::new(),
+        };
+
+        report.add(
+            "routes_without_short_name_or_agency",
+            gtfs.routes
+                .values()
+                .filter(|route| route.short_name.is_none() || route.agency_id.is_none())
+                .map(|route| route.id.clone()),
+        );
+
+        report.add(
+            "trips_without_shape",
+            gtfs.trips
+                .values()
+                .filter(|trip| {
+                    trip.shape_id
+                        .as_ref()
+                        .is_none_or(|shape_id| !gtfs.shapes.contains_key(shape_id))
+                })
+                .map(|trip| trip.id.clone()),
+        );
+
+        report.add(
+            "trips_without_stop_times",
+            gtfs.trips
+                .values()
+                .filter(|trip| trip.stop_times.is_empty())
+                .map(|trip| trip.id.clone()),
+        );
+
+        report.add(
+            "trips_not_matched_to_shape",
+            gtfs.trips
+                .values()
+                .filter(|trip| trip.shape_id.is_some() && !trip.stop_times.is_empty())
+                .filter(|trip| index.get_trip(&trip.id).is_none())
+                .map(|trip| trip.id.clone()),
+        );
+
+        report.add(
+            "stop_times_without_coordinates",
+            gtfs.trips.values().flat_map(|trip| {
+                trip.stop_times
+                    .iter()
+                    .filter(|stop_time| {
+                        stop_time.stop.latitude.is_none() || stop_time.stop.longitude.is_none()
+                    })
+                    .map(|stop_time| {
+                        format!(
+                            "{}#{} ({})",
+                            trip.id, stop_time.stop_sequence, stop_time.stop.id
+                        )
+                    })
+            }),
+        );
+
+        report.add(
+            "stop_times_without_arrival_time",
+            gtfs.trips.values().flat_map(|trip| {
+                trip.stop_times
+                    .iter()
+                    .filter(|stop_time| stop_time.arrival_time.is_none())
+                    .map(|stop_time| format!("{}#{}", trip.id, stop_time.stop_sequence))
+            }),
+        );
+
+        report
+    }
+
+    fn add(&mut self, category: &'static str, ids: impl Iterator<Item = String>) {
+        let mut ids = ids.collect::<Vec<String>>();
+        if ids.is_empty() {
+            return;
+        }
+
+        ids.sort_unstable();
+        self.issues.insert(
+            category,
+            Issue {
+                count: ids.len(),
+                examples: ids.into_iter().take(MAX_EXAMPLES).collect(),
+            },
+        );
+    }
+
+    pub fn log(&self) {
+        if self.issues.is_empty() {
+            logger::fine("VALIDATION", "No issue found in the GTFS");
+            return;
+        }
+
+        for (category, issue) in &self.issues {
+            logger::warn(
+                "VALIDATION",
+                &format!(
+                    "{}: {} (e.g. {})",
+                    category,
+                    issue.count,
+                    issue.examples.join(", ")
+                ),
+            );
+        }
+    }
+}